@@ -0,0 +1,76 @@
+#[path = "../src/fixtures.rs"]
+mod fixtures;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use proc_mem_to_prom::{aggregate, parse_status, MemoryUnit, ProcInfo};
+use procfs::process::Status;
+use fixtures::Fixture;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use users::UsersCache;
+
+// count allocations so we can report them alongside the timings
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn synthetic_status(pid: i32, uid: u32) -> Status {
+    let fixture = Fixture {
+        name: "bench".to_string(),
+        rss_kib: Some(1024 + pid as u64 % 4096),
+        swap_kib: pid as u64 % 64,
+        threads: 1 + pid as u32 % 16,
+        ..Fixture::new(pid, uid)
+    };
+    Status::from_reader(fixture.status().as_bytes()).unwrap()
+}
+
+fn synthetic_processes(n: i32) -> Vec<ProcInfo> {
+    // spread the processes over a few hundred uids, like a busy shared node
+    (0..n).map(|pid| synthetic_status(pid, 1000 + (pid % 300) as u32).into()).collect()
+}
+
+fn bench_aggregate(c: &mut Criterion) {
+    let usernames = UsersCache::new();
+    let mut group = c.benchmark_group("aggregate");
+    for n in [10_000, 40_000, 100_000] {
+        let processes = synthetic_processes(n);
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        aggregate(&usernames, &processes, MemoryUnit::Bytes);
+        let allocs = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!("aggregate/{}: {} allocations per run", n, allocs);
+
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &processes, |b, processes| {
+            b.iter(|| aggregate(&usernames, processes, MemoryUnit::Bytes))
+        });
+    }
+    group.finish();
+}
+
+fn bench_parse_status(c: &mut Criterion) {
+    // a real status file has many more fields than the synthetic ones
+    let text = std::fs::read("/proc/self/status").unwrap();
+    let mut group = c.benchmark_group("parse_status");
+    group.bench_function("procfs", |b| b.iter(|| Status::from_reader(text.as_slice()).unwrap()));
+    group.bench_function("filtered", |b| b.iter(|| parse_status(text.as_slice()).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_aggregate, bench_parse_status);
+criterion_main!(benches);
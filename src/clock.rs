@@ -0,0 +1,28 @@
+//! Clock tick conversion for the tick-based fields of /proc/<pid>/stat,
+//! such as utime, stime, and starttime.
+//!
+//! The tick rate is usually 100, but not always, so it is read from
+//! `sysconf(_SC_CLK_TCK)` once rather than assumed.
+
+use std::sync::OnceLock;
+
+// used if sysconf fails, which it shouldn't on Linux
+const DEFAULT_TICKS_PER_SECOND: u64 = 100;
+
+/// The number of clock ticks per second.
+pub fn ticks_per_second() -> u64 {
+    static TICKS: OnceLock<u64> = OnceLock::new();
+    *TICKS.get_or_init(|| {
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks > 0 {
+            ticks as u64
+        } else {
+            DEFAULT_TICKS_PER_SECOND
+        }
+    })
+}
+
+/// Convert clock ticks to seconds.
+pub fn ticks_to_seconds(ticks: u64) -> f64 {
+    ticks as f64 / ticks_per_second() as f64
+}
@@ -0,0 +1,134 @@
+//! Synthetic procfs files, shared by the library's and the binary's tests
+//! and the benchmarks, which each include this file as a module.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// What a fixture procfs has for one process.
+#[derive(Clone)]
+pub struct Fixture {
+    pub pid: i32,
+    pub ppid: i32,
+    pub name: String,
+    pub state: char,
+    // the whole Uid value, so a test can garble it
+    pub uid: String,
+    pub gid: u32,
+    // kernel threads have no Vm* lines at all
+    pub rss_kib: Option<u64>,
+    pub swap_kib: u64,
+    pub threads: u32,
+    pub nice: i64,
+    pub policy: u32,
+    pub tty_nr: i32,
+    // in clock ticks since boot
+    pub starttime: u64,
+    pub cmdline: Vec<String>,
+    // the tid and state of each task/<tid>; none means no task directory
+    pub tasks: Vec<(i32, char)>
+}
+
+impl Fixture {
+    /// A sleeping, single-threaded process with 1 MiB RSS, started at boot.
+    pub fn new(pid: i32, uid: u32) -> Fixture {
+        Fixture {
+            pid,
+            ppid: 1,
+            name: format!("p{}", pid),
+            state: 'S',
+            uid: format!("{uid}\t{uid}\t{uid}\t{uid}"),
+            gid: uid,
+            rss_kib: Some(1024),
+            swap_kib: 0,
+            threads: 1,
+            nice: 0,
+            policy: 0,
+            tty_nr: 0,
+            starttime: 0,
+            cmdline: vec![format!("/usr/bin/p{}", pid)],
+            tasks: Vec::new()
+        }
+    }
+
+    /// The /proc/<pid>/status, with the fields procfs requires.
+    pub fn status(&self) -> String {
+        let state = match self.state {
+            'R' => "R (running)",
+            'D' => "D (disk sleep)",
+            'T' => "T (stopped)",
+            'Z' => "Z (zombie)",
+            _ => "S (sleeping)"
+        };
+        let memory = match self.rss_kib {
+            Some(rss) => format!("VmRSS:\t{} kB\nRssShmem:\t0 kB\nVmSwap:\t{} kB\n", rss, self.swap_kib),
+            None => String::new()
+        };
+        format!("Name:\t{name}\nState:\t{state}\nTgid:\t{pid}\nPid:\t{pid}\nPPid:\t{ppid}\n\
+            TracerPid:\t0\nUid:\t{uid}\nGid:\t{gid}\t{gid}\t{gid}\t{gid}\nFDSize:\t64\nGroups:\t\n\
+            {memory}Threads:\t{threads}\nSigQ:\t0/63008\nSigPnd:\t0000000000000000\nShdPnd:\t0000000000000000\n\
+            SigBlk:\t0000000000000000\nSigIgn:\t0000000000000000\nSigCgt:\t0000000000000000\n\
+            CapInh:\t0000000000000000\nCapPrm:\t0000000000000000\nCapEff:\t0000000000000000\n",
+            name = self.name, pid = self.pid, ppid = self.ppid, uid = self.uid, gid = self.gid, threads = self.threads)
+    }
+
+    /// The /proc/<pid>/stat, or a task's with `tid` and `state`, with all
+    /// 52 fields of a current kernel.
+    pub fn stat(&self, tid: i32, state: char) -> String {
+        format!("{tid} ({name}) {state} {ppid} {pid} {pid} {tty_nr} -1 0 0 0 0 0 0 0 0 0 {priority} {nice} {threads} 0 \
+            {starttime} 0 {rss} 18446744073709551615 0 0 0 0 0 0 0 0 0 0 0 0 17 0 0 {policy} 0 0 0 0 0 0 0 0 0 0 0\n",
+            name = self.name, ppid = self.ppid, pid = self.pid, tty_nr = self.tty_nr, priority = 20 + self.nice,
+            nice = self.nice, threads = self.threads, starttime = self.starttime,
+            rss = self.rss_kib.unwrap_or(0) / 4, policy = self.policy)
+    }
+}
+
+// the seconds since boot in a fixture procfs's uptime
+pub const UPTIME: f64 = 1000.0;
+
+/// A procfs in a temporary directory, removed when dropped.
+pub struct FixtureProcfs {
+    pub root: PathBuf
+}
+
+impl FixtureProcfs {
+    /// Write the processes, named so concurrent tests don't collide.
+    pub fn new(name: &str, processes: &[Fixture]) -> io::Result<FixtureProcfs> {
+        let root = env::temp_dir().join(format!("proc-mem-to-prom-{}-{}", name, std::process::id()));
+        let procfs = FixtureProcfs{root};
+        fs::create_dir_all(&procfs.root)?;
+        fs::write(procfs.root.join("uptime"), format!("{:.2} {:.2}\n", UPTIME, UPTIME))?;
+        for process in processes {
+            procfs.add(process)?;
+        }
+        Ok(procfs)
+    }
+
+    pub fn add(&self, process: &Fixture) -> io::Result<()> {
+        let dir = self.root.join(process.pid.to_string());
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("status"), process.status())?;
+        fs::write(dir.join("stat"), process.stat(process.pid, process.state))?;
+        let cmdline: String = process.cmdline.iter().map(|arg| format!("{}\0", arg)).collect();
+        fs::write(dir.join("cmdline"), cmdline)?;
+        for &(tid, state) in &process.tasks {
+            let task = dir.join("task").join(tid.to_string());
+            fs::create_dir_all(&task)?;
+            fs::write(task.join("stat"), process.stat(tid, state))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a process, as if it exited.
+    pub fn remove(&self, pid: i32) -> io::Result<()> {
+        fs::remove_dir_all(self.root.join(pid.to_string()))
+    }
+}
+
+impl Drop for FixtureProcfs {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
@@ -0,0 +1,140 @@
+//! Graphite plaintext output.
+//!
+//! Each sample becomes a `path value timestamp` line, with the path built
+//! from the job, hostgroup, and instance (by whatever names they have),
+//! the metric name, and then any
+//! remaining label values, e.g.
+//! `proc-mem-to-prom.test.node1.node_user_processes_rss.alice`.
+
+use log::{error, warn};
+use proc_mem_to_prom::LabelNames;
+use prometheus::proto::{MetricFamily, MetricType};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+// unsent lines are kept across intervals, the oldest dropped beyond this
+const MAX_BUFFERED: usize = 4 * 1024 * 1024;
+
+// scrapes wait for the send, so a peer that stops reading mustn't stall them
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Graphite {
+    addr: String,
+    names: LabelNames,
+    state: Mutex<State>
+}
+
+struct State {
+    conn: Option<TcpStream>,
+    pending: Vec<u8>
+}
+
+impl Graphite {
+    pub fn new(addr: &str, names: LabelNames) -> Graphite {
+        Graphite {
+            addr: addr.to_string(),
+            names,
+            state: Mutex::new(State{conn: None, pending: Vec::new()})
+        }
+    }
+
+    /// Send the families, reconnecting if needed. Lines that can't be sent
+    /// are kept and retried on the next call.
+    pub async fn send(&self, families: &[MetricFamily]) {
+        let mut state = self.state.lock().await;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        state.pending.extend(encode(families, &self.names, timestamp));
+        if state.pending.len() > MAX_BUFFERED {
+            // drop whole lines from the front, keeping the newest
+            let excess = state.pending.len() - MAX_BUFFERED;
+            let cut = state.pending[excess..].iter().position(|&b| b == b'\n').map_or(state.pending.len(), |i| excess + i + 1);
+            warn!("graphite buffer for {} is full, dropping the oldest {} bytes", self.addr, cut);
+            state.pending.drain(..cut);
+        }
+
+        if state.conn.is_none() {
+            match timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.addr)).await {
+                Ok(Ok(conn)) => state.conn = Some(conn),
+                Ok(Err(e)) => {
+                    error!("cannot connect to graphite at {}: {}", self.addr, e);
+                    return;
+                },
+                Err(_) => {
+                    error!("cannot connect to graphite at {}: no connection in {:?}", self.addr, CONNECT_TIMEOUT);
+                    return;
+                }
+            }
+        }
+
+        let State{conn, pending} = &mut *state;
+        let stream = conn.as_mut().unwrap();
+        // sent bytes leave the buffer as they go, so a failure leaves
+        // exactly what is unsent
+        let mut mid_line = false;
+        let sent = timeout(SEND_TIMEOUT, async {
+            while !pending.is_empty() {
+                let n = stream.write(pending).await?;
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero));
+                }
+                mid_line = pending[n - 1] != b'\n';
+                pending.drain(..n);
+            }
+            Ok(())
+        }).await;
+        let e = match sent {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("not sent in {:?}", SEND_TIMEOUT)
+        };
+        error!("cannot send to graphite at {}: {}", self.addr, e);
+        *conn = None;
+        if mid_line {
+            // the rest of a line the peer got the start of would be garbled
+            // on a new connection
+            let end = pending.iter().position(|&b| b == b'\n').map_or(pending.len(), |i| i + 1);
+            pending.drain(..end);
+        }
+    }
+}
+
+/// Replace characters that would break up a graphite path.
+fn path_component(s: &str) -> String {
+    s.chars().map(|c| match c {
+        '.' | ' ' | '\t' | '\n' | '/' => '_',
+        c => c
+    }).collect()
+}
+
+pub fn encode(families: &[MetricFamily], names: &LabelNames, timestamp: u64) -> Vec<u8> {
+    let mut out = String::new();
+    for family in families {
+        for m in family.get_metric() {
+            let value = match family.get_field_type() {
+                MetricType::GAUGE => m.get_gauge().get_value(),
+                MetricType::COUNTER => m.get_counter().get_value(),
+                MetricType::UNTYPED => m.get_untyped().get_value(),
+                _ => continue
+            };
+            let label = |name: &str| m.get_label().iter()
+                .find(|l| l.get_name() == name)
+                .map(|l| path_component(l.get_value()));
+            let mut path: Vec<String> = names.iter()
+                .filter_map(|name| label(name))
+                .collect();
+            path.push(path_component(family.get_name()));
+            for l in m.get_label() {
+                if !names.contains(&l.get_name()) {
+                    path.push(path_component(l.get_value()));
+                }
+            }
+            out.push_str(&format!("{} {} {}\n", path.join("."), value, timestamp));
+        }
+    }
+    out.into_bytes()
+}
@@ -0,0 +1,1372 @@
+//! Per-user process aggregation and metric publishing, kept apart from the
+//! live /proc read so both can be driven with synthetic data.
+
+pub mod clock;
+
+use procfs::process::Status;
+use procfs::ProcResult;
+use prometheus::core::Collector;
+use prometheus::{histogram_opts, opts};
+use prometheus::{CounterVec, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, Registry};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use users::{User, Users, UsersCache};
+
+// like prometheus' register_*_with_registry!, but returning a bad label
+// list (such as a duplicate name) as an error instead of panicking
+macro_rules! register {
+    ($type:ty, $opts:expr, $labels:expr, $registry:expr) => {{
+        let collector = <$type>::new($opts, $labels)?;
+        $registry.register(Box::new(collector.clone())).map(|_| collector)
+    }};
+}
+
+/// What we read for a single process.
+pub struct ProcInfo {
+    pub status: Status,
+    // only read with --count-threads
+    pub blocked_threads: Option<i64>,
+    // only read with --group-by-tty
+    pub tty: Option<String>,
+    // only read with --group-by-loginuid
+    pub loginuid: Option<u32>,
+    // only read with --detect-containers
+    pub pid_ns: Option<&'static str>,
+    // the euid as a label, only with --include-uid-label
+    pub uid: Option<u32>,
+    // from /proc/<pid>/stat, if readable
+    pub nice: Option<i64>,
+    // only read with --group-by-exe
+    pub exe: Option<String>,
+    // the command name, only with --group-by-user-command
+    pub command: Option<String>,
+    // whether the state is one of --disk-sleep-states
+    pub disk_sleep: bool,
+    // the values of --extra-status-field fields, in order
+    pub extra_fields: Vec<FieldValue>,
+    // the scheduling policy from /proc/<pid>/stat, if readable
+    pub sched_class: Option<String>,
+    // whether the RSS is over --big-process-bytes, if set
+    pub over_threshold: Option<bool>,
+    // whether more fds are open than --high-fd-threshold, if set and readable
+    pub high_fd: Option<bool>,
+    // whether vmswap was summed from smaps, with --swap-fallback
+    pub swap_fallback: bool,
+    // resident KiB per NUMA node from numa_maps, only read with --numa
+    pub numa_kib: HashMap<String, u64>
+}
+
+/// The loginuid of processes that were not started from a login.
+pub const LOGINUID_UNSET: u32 = u32::MAX;
+
+impl From<Status> for ProcInfo {
+    fn from(status: Status) -> ProcInfo {
+        ProcInfo {
+            status,
+            blocked_threads: None,
+            tty: None,
+            loginuid: None,
+            pid_ns: None,
+            uid: None,
+            nice: None,
+            exe: None,
+            command: None,
+            disk_sleep: false,
+            extra_fields: Vec::new(),
+            sched_class: None,
+            over_threshold: None,
+            high_fd: None,
+            swap_fallback: false,
+            numa_kib: HashMap::new()
+        }
+    }
+}
+
+/// Get the device name for a controlling terminal, from the
+/// major and minor numbers of `Stat::tty_nr`.
+pub fn tty_name(major: i32, minor: i32) -> String {
+    match major {
+        0 => "none".to_string(),
+        4 if minor < 64 => format!("tty{}", minor),
+        4 => format!("ttyS{}", minor - 64),
+        5 if minor == 0 => "tty".to_string(),
+        5 if minor == 1 => "console".to_string(),
+        136..=143 => format!("pts/{}", (major - 136) * 256 + minor),
+        _ => format!("{}:{}", major, minor)
+    }
+}
+
+#[derive(Default)]
+pub struct ProcEntry {
+    pub count: i64,
+    pub rss: i64,
+    pub swap: i64,
+    pub shmem: i64,
+    pub locked: i64,
+    pub page_tables: i64,
+    pub max_threads: i64,
+    // only kept for users, the most direct children of one process
+    pub max_children: i64,
+    pub blocked: i64,
+    pub blocked_threads: Option<i64>,
+    pub disk_sleep: i64,
+    pub rt: i64,
+    pub stopped: i64,
+    pub over_threshold: Option<i64>,
+    pub high_fd: Option<i64>,
+    pub sched_classes: HashMap<String, i64>,
+    // the RSS per NUMA node, only with --numa
+    pub numa: HashMap<String, i64>,
+    pub extra_fields: Vec<i64>,
+    // only kept for users, for churn counting
+    pub pids: Vec<i32>,
+    pub min_nice: Option<i64>,
+    pub max_nice: Option<i64>
+}
+
+/// The unit memory gauges are reported in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryUnit {
+    Bytes,
+    /// The raw unit of /proc, for dashboards that predate bytes
+    Kib
+}
+
+impl MemoryUnit {
+    /// Convert a /proc memory field, which is in KiB.
+    pub fn convert(self, kib: Option<u64>) -> i64 {
+        let kib = kib.unwrap_or(0) as i64;
+        match self {
+            MemoryUnit::Bytes => kib * 1024,
+            MemoryUnit::Kib => kib
+        }
+    }
+
+    /// Get the suffix for metric names, so a unit change can't silently
+    /// break dashboards. Bytes keeps the original names.
+    fn suffix(self) -> &'static str {
+        match self {
+            MemoryUnit::Bytes => "",
+            MemoryUnit::Kib => "_kib"
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            MemoryUnit::Bytes => "bytes",
+            MemoryUnit::Kib => "KiB"
+        }
+    }
+}
+
+/// What each observation of the RSS histogram is the RSS of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RssHistogramScope {
+    Process,
+    User
+}
+
+impl RssHistogramScope {
+    fn name(self, unit: MemoryUnit) -> String {
+        let subject = match self {
+            RssHistogramScope::Process => "node_process_rss",
+            RssHistogramScope::User => "node_user_total_rss"
+        };
+        match unit {
+            MemoryUnit::Bytes => format!("{}_bytes", subject),
+            MemoryUnit::Kib => format!("{}_kib", subject)
+        }
+    }
+}
+
+/// The default RSS histogram buckets, from 1 MiB to 256 GiB by 4x.
+pub fn default_rss_buckets(unit: MemoryUnit) -> Vec<f64> {
+    let start = match unit {
+        MemoryUnit::Bytes => 1024.0 * 1024.0,
+        MemoryUnit::Kib => 1024.0
+    };
+    prometheus::exponential_buckets(start, 4.0, 10).unwrap()
+}
+
+impl ProcEntry {
+    fn add(&mut self, info: &ProcInfo, unit: MemoryUnit) {
+        let process = &info.status;
+        self.count += 1;
+        self.rss += unit.convert(process.vmrss);
+        self.swap += unit.convert(process.vmswap);
+        self.shmem += unit.convert(process.rssshmem);
+        self.locked += unit.convert(process.vmlck);
+        self.page_tables += unit.convert(process.vmpte);
+        self.max_threads = self.max_threads.max(process.threads as i64);
+        if process.state.starts_with('D') {
+            self.blocked += 1;
+        }
+        if let Some(x) = info.blocked_threads {
+            *self.blocked_threads.get_or_insert(0) += x;
+        }
+        if info.disk_sleep {
+            self.disk_sleep += 1;
+        }
+        // T is stopped by a signal, t is stopped by a tracer
+        if process.state.starts_with(['T', 't']) {
+            self.stopped += 1;
+        }
+        self.extra_fields.resize(info.extra_fields.len(), 0);
+        for (sum, field) in self.extra_fields.iter_mut().zip(&info.extra_fields) {
+            *sum += if field.kib { unit.convert(Some(field.value)) } else { field.value as i64 };
+        }
+        if let Some(class) = &info.sched_class {
+            if class == "rt" {
+                self.rt += 1;
+            }
+            *self.sched_classes.entry(class.clone()).or_insert(0) += 1;
+        }
+        for (node, kib) in &info.numa_kib {
+            *self.numa.entry(node.clone()).or_insert(0) += unit.convert(Some(*kib));
+        }
+        if let Some(over) = info.over_threshold {
+            *self.over_threshold.get_or_insert(0) += over as i64;
+        }
+        if let Some(high) = info.high_fd {
+            *self.high_fd.get_or_insert(0) += high as i64;
+        }
+        if let Some(x) = info.nice {
+            self.min_nice = Some(self.min_nice.map_or(x, |n| n.min(x)));
+            self.max_nice = Some(self.max_nice.map_or(x, |n| n.max(x)));
+        }
+    }
+
+    /// Fold another entry into this one, as if its processes were added.
+    pub fn merge(&mut self, other: ProcEntry) {
+        let add = |a: &mut Option<i64>, b: Option<i64>| if let Some(b) = b { *a.get_or_insert(0) += b; };
+        let min = |a: Option<i64>, b: Option<i64>| a.into_iter().chain(b).min();
+        let max = |a: Option<i64>, b: Option<i64>| a.into_iter().chain(b).max();
+        self.count += other.count;
+        self.rss += other.rss;
+        self.swap += other.swap;
+        self.shmem += other.shmem;
+        self.locked += other.locked;
+        self.page_tables += other.page_tables;
+        self.max_threads = self.max_threads.max(other.max_threads);
+        self.max_children = self.max_children.max(other.max_children);
+        self.blocked += other.blocked;
+        add(&mut self.blocked_threads, other.blocked_threads);
+        self.disk_sleep += other.disk_sleep;
+        self.rt += other.rt;
+        self.stopped += other.stopped;
+        add(&mut self.over_threshold, other.over_threshold);
+        add(&mut self.high_fd, other.high_fd);
+        for (class, count) in other.sched_classes {
+            *self.sched_classes.entry(class).or_insert(0) += count;
+        }
+        for (node, rss) in other.numa {
+            *self.numa.entry(node).or_insert(0) += rss;
+        }
+        self.extra_fields.resize(self.extra_fields.len().max(other.extra_fields.len()), 0);
+        for (sum, x) in self.extra_fields.iter_mut().zip(other.extra_fields) {
+            *sum += x;
+        }
+        self.pids.extend(other.pids);
+        self.min_nice = min(self.min_nice, other.min_nice);
+        self.max_nice = max(self.max_nice, other.max_nice);
+    }
+}
+
+/// Trim a username and replace the whitespace inside it with underscores,
+/// for names from directory services such as "Jane Doe".
+pub fn sanitize_username(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+/// What per-user series are keyed by: the username, then the values of
+/// any extra per-user labels that are enabled, such as pid_ns and uid.
+pub type UserKey = Vec<String>;
+
+#[derive(Default)]
+pub struct Aggregate {
+    pub users: HashMap<UserKey, ProcEntry>,
+    pub orphaned_uids: HashMap<String, i64>,
+    // processes with no user, including those with no usable Uid
+    pub unknown_processes: i64,
+    pub ttys: HashMap<String, ProcEntry>,
+    pub loginusers: HashMap<String, ProcEntry>,
+    pub exes: HashMap<String, ProcEntry>,
+    // keyed by [username, command]
+    pub user_commands: HashMap<Vec<String>, ProcEntry>
+}
+
+/// Keep the `n` groups with the most RSS, folding the rest into `other`.
+pub fn top_n<K: Eq + std::hash::Hash>(groups: HashMap<K, ProcEntry>, n: usize, other: K) -> HashMap<K, ProcEntry> {
+    if groups.len() <= n {
+        return groups;
+    }
+    let mut sorted: Vec<_> = groups.into_iter().collect();
+    sorted.sort_by_key(|x| std::cmp::Reverse(x.1.rss));
+    let rest = sorted.split_off(n);
+    let mut groups: HashMap<_, _> = sorted.into_iter().collect();
+    let other = groups.entry(other).or_insert_with(ProcEntry::default);
+    for (_, entry) in rest {
+        other.merge(entry);
+    }
+    groups
+}
+
+// the /proc/<pid>/status fields Status::from_reader requires, and the
+// memory fields; the rest (about two thirds) are never parsed. The memory
+// fields feed gauges that are always registered, so unlike the
+// --extra-status-field fields they aren't gated on any flag
+const STATUS_FIELDS: &[&str] = &[
+    "Name", "State", "Tgid", "Pid", "PPid", "TracerPid", "Uid", "Gid",
+    "FDSize", "Groups", "Threads", "SigQ", "SigPnd", "ShdPnd", "SigBlk",
+    "SigIgn", "SigCgt", "CapInh", "CapPrm", "CapEff",
+    "VmRSS", "VmSwap", "RssShmem", "VmLck", "VmPTE"
+];
+
+/// Parse /proc/<pid>/status, skipping the fields we don't use.
+/// procfs can't parse only some fields, so we drop the other lines first.
+pub fn parse_status<R: Read>(r: R) -> ProcResult<Status> {
+    Ok(parse_status_with(r, &[])?.0)
+}
+
+/// A numeric /proc/<pid>/status field that procfs doesn't give us.
+#[derive(Clone, Copy, Default)]
+pub struct FieldValue {
+    pub value: u64,
+    // whether the field is in kB, like VmLck
+    pub kib: bool
+}
+
+impl FieldValue {
+    fn parse(value: &str) -> Option<FieldValue> {
+        let value = value.trim();
+        Some(match value.strip_suffix(" kB") {
+            Some(kib) => FieldValue{value: kib.trim().parse().ok()?, kib: true},
+            None => FieldValue{value: value.parse().ok()?, kib: false}
+        })
+    }
+}
+
+/// The uid and gid given to a process whose status has no usable Uid or
+/// Gid line. (uid_t)-1 is never a real id, so it is counted as unknown
+/// rather than as root.
+pub const ID_MISSING: u32 = u32::MAX;
+
+// whether a Uid or Gid value has the real, effective, saved, and fs ids
+fn well_formed_ids(value: &str) -> bool {
+    let ids: Vec<_> = value.split_whitespace().collect();
+    ids.len() == 4 && ids.iter().all(|id| id.parse::<u32>().is_ok())
+}
+
+/// Parse /proc/<pid>/status like `parse_status`, also getting the values
+/// of `extra` fields. Fields a process lacks (kernel threads have no Vm*)
+/// are zero. A missing or garbled Uid or Gid, as from a racing read, is
+/// `ID_MISSING`; the other fields procfs requires must be there.
+pub fn parse_status_with<R: Read>(mut r: R, extra: &[String]) -> ProcResult<(Status, Vec<FieldValue>)> {
+    let mut text = String::new();
+    r.read_to_string(&mut text)?;
+    let mut wanted = String::with_capacity(text.len());
+    let mut values = vec![FieldValue::default(); extra.len()];
+    let mut ids = [("Uid", false), ("Gid", false)];
+    for line in text.lines() {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        if let Some((_, seen)) = ids.iter_mut().find(|(name, _)| *name == field) {
+            if !well_formed_ids(value) {
+                continue;
+            }
+            *seen = true;
+        }
+        if STATUS_FIELDS.contains(&field) {
+            wanted.push_str(line);
+            wanted.push('\n');
+        }
+        if let Some(i) = extra.iter().position(|x| x == field) {
+            values[i] = FieldValue::parse(value).unwrap_or_default();
+        }
+    }
+    for (name, seen) in ids {
+        if !seen {
+            wanted.push_str(&format!("{}:\t{id}\t{id}\t{id}\t{id}\n", name, id = ID_MISSING));
+        }
+    }
+    Ok((Status::from_reader(wanted.as_bytes())?, values))
+}
+
+/// Check that a status field exists and is numeric, using our own status.
+pub fn probe_status_field(field: &str) -> Result<(), String> {
+    let text = std::fs::read_to_string("/proc/self/status").map_err(|e| e.to_string())?;
+    for line in text.lines() {
+        if let Some((f, value)) = line.split_once(':') {
+            if f == field {
+                return match FieldValue::parse(value) {
+                    Some(_) => Ok(()),
+                    None => Err(format!("status field {} is not a number: {:?}", field, value.trim()))
+                };
+            }
+        }
+    }
+    Err(format!("no status field {} in /proc/self/status", field))
+}
+
+/// Name a scheduling policy, as in sched_setscheduler(2).
+pub fn sched_class_name(policy: u32) -> String {
+    match policy {
+        0 => "normal".to_string(),
+        1 | 2 => "rt".to_string(),
+        3 => "batch".to_string(),
+        5 => "idle".to_string(),
+        6 => "deadline".to_string(),
+        other => other.to_string()
+    }
+}
+
+pub fn username_for(user: &Option<Arc<User>>) -> &str {
+    match user {
+        Some(x) => x.name().to_str().unwrap(),
+        None => "unknown"
+    }
+}
+
+/// Sum up the processes by user and the enabled groupings.
+///
+/// Processes are attributed by the euid from their own status read, the
+/// same read the memory fields come from, so a process that changes euid
+/// mid-scan (e.g. a setuid helper) is counted wholly for one user or the
+/// other, never split between them. It can still move between users from
+/// one scan to the next.
+pub fn aggregate(usernames: &UsersCache, processes: &[ProcInfo], unit: MemoryUnit) -> Aggregate {
+    let children = count_children(processes.iter().map(|info| info.status.ppid));
+    aggregate_with_children(usernames, processes, &children, unit)
+}
+
+/// Count the direct children of each pid, from the PPid of every process.
+pub fn count_children(ppids: impl IntoIterator<Item = i32>) -> HashMap<i32, i64> {
+    let mut children = HashMap::new();
+    for ppid in ppids {
+        *children.entry(ppid).or_insert(0) += 1;
+    }
+    children
+}
+
+/// Aggregate like `aggregate`, taking the children of each pid from
+/// `children`, which can count processes that were filtered out.
+pub fn aggregate_with_children(usernames: &UsersCache, processes: &[ProcInfo], children: &HashMap<i32, i64>, unit: MemoryUnit) -> Aggregate {
+    let mut agg = Aggregate::default();
+
+    for info in processes {
+        let process = &info.status;
+        let user = usernames.get_user_by_uid(process.euid);
+        if user.is_none() {
+            agg.unknown_processes += 1;
+            // a racing read with no usable Uid is not a deleted user
+            if process.euid != ID_MISSING {
+                *agg.orphaned_uids.entry(process.euid.to_string()).or_insert(0) += 1;
+            }
+        }
+        let mut key = vec![username_for(&user).to_string()];
+        if let Some(pid_ns) = info.pid_ns {
+            key.push(pid_ns.to_string());
+        }
+        if let Some(uid) = info.uid {
+            key.push(uid.to_string());
+        }
+        let entry = agg.users.entry(key).or_insert_with(ProcEntry::default);
+        entry.add(info, unit);
+        entry.pids.push(process.pid);
+        entry.max_children = entry.max_children.max(children.get(&process.pid).copied().unwrap_or(0));
+        if let Some(tty) = &info.tty {
+            agg.ttys.entry(tty.clone()).or_insert_with(ProcEntry::default).add(info, unit);
+        }
+        if let Some(loginuid) = info.loginuid {
+            let loginuser = match loginuid {
+                LOGINUID_UNSET => "none".to_string(),
+                uid => username_for(&usernames.get_user_by_uid(uid)).to_string()
+            };
+            agg.loginusers.entry(loginuser).or_insert_with(ProcEntry::default).add(info, unit);
+        }
+        if let Some(exe) = &info.exe {
+            agg.exes.entry(exe.clone()).or_insert_with(ProcEntry::default).add(info, unit);
+        }
+        if let Some(command) = &info.command {
+            let key = vec![username_for(&user).to_string(), command.clone()];
+            agg.user_commands.entry(key).or_insert_with(ProcEntry::default).add(info, unit);
+        }
+    }
+    agg
+}
+
+/// The names of the labels every series has, `DEFAULT_LABEL_NAMES`
+/// unless renamed.
+pub type LabelNames = [&'static str; 3];
+
+pub const DEFAULT_LABEL_NAMES: LabelNames = ["job", "hostgroup", "instance"];
+
+/// The label values shared by every series.
+pub struct Labels<'a> {
+    pub job: &'a str,
+    pub hostgroup: &'a str,
+    pub instance: &'a str
+}
+
+impl<'a> Labels<'a> {
+    pub fn values(&self) -> [&'a str; 3] {
+        [self.job, self.hostgroup, self.instance]
+    }
+
+    pub fn with<'b>(&self, extra: &'b [String]) -> Vec<&'b str> where 'a: 'b {
+        let mut values = self.values().to_vec();
+        values.extend(extra.iter().map(|x| x.as_str()));
+        values
+    }
+}
+
+/// A per-user gauge, and how to get its value for a user.
+/// Users with no value have no series, e.g. if the metric is disabled.
+struct UserGauge {
+    gauge: IntGaugeVec,
+    // the unsmoothed values, only with --ema-alpha
+    raw: Option<IntGaugeVec>,
+    // the change since the last scan, only with --emit-deltas
+    delta: Option<IntGaugeVec>,
+    value: fn(&ProcEntry) -> Option<i64>,
+    // leave out series that are zero
+    omit_zero: bool,
+    // the flag without which there are no series
+    flag: Option<&'static str>
+}
+
+/// Exponential moving average of each per-user series across scans.
+struct Ema {
+    alpha: f64,
+    // keyed by the index into `Metrics::user_gauges` and all label values
+    values: Mutex<HashMap<(usize, Vec<String>), f64>>
+}
+
+impl Ema {
+    /// Smooth the values of every user gauge, forgetting users that are
+    /// no longer present so they start over if they come back.
+    fn smooth<'a>(&self, labels: &Labels, values: Vec<Vec<(&'a UserKey, i64)>>) -> Vec<Vec<(&'a UserKey, i64)>> {
+        let mut prev = self.values.lock().unwrap();
+        let mut next = HashMap::new();
+        let smoothed = values.into_iter().enumerate().map(|(i, series)| {
+            series.into_iter().map(|(key, value)| {
+                let k = (i, labels.with(key).iter().map(|x| x.to_string()).collect());
+                let value = value as f64;
+                let avg = match prev.remove(&k) {
+                    Some(p) => self.alpha * value + (1.0 - self.alpha) * p,
+                    None => value
+                };
+                next.insert(k, avg);
+                (key, avg.round() as i64)
+            }).collect()
+        }).collect();
+        // keep the other targets' values
+        prev.retain(|(_, values), _| !values.iter().zip(labels.values()).all(|(a, b)| a == b));
+        prev.extend(next);
+        smoothed
+    }
+}
+
+// RSS samples, oldest first
+type Samples = VecDeque<(Instant, i64)>;
+
+/// The largest RSS of each user over a sliding window of scans.
+struct RssWindow {
+    gauge: IntGaugeVec,
+    window: Duration,
+    // each user's samples, keyed by all label values
+    samples: Mutex<HashMap<Vec<String>, Samples>>
+}
+
+impl RssWindow {
+    /// Add this scan's RSS and publish the maximum over the window.
+    /// Users that are no longer present are forgotten.
+    fn publish(&self, labels: &Labels, users: &HashMap<UserKey, ProcEntry>) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        let mut next = HashMap::new();
+        let mut series = Vec::new();
+        for (key, entry) in users {
+            let k: Vec<String> = labels.with(key).iter().map(|x| x.to_string()).collect();
+            let mut window = samples.remove(&k).unwrap_or_default();
+            while window.front().is_some_and(|(at, _)| now.duration_since(*at) > self.window) {
+                window.pop_front();
+            }
+            window.push_back((now, entry.rss));
+            series.push((labels.with(key), window.iter().map(|(_, rss)| *rss).max().unwrap_or(0)));
+            next.insert(k, window);
+        }
+        // keep the other targets' samples
+        samples.retain(|values, _| !values.iter().zip(labels.values()).all(|(a, b)| a == b));
+        samples.extend(next);
+        set_series(&self.gauge, labels, series);
+    }
+}
+
+/// The change of each per-user series since the previous scan.
+#[derive(Default)]
+struct Deltas {
+    // keyed like `Ema::values`
+    previous: Mutex<HashMap<(usize, Vec<String>), i64>>
+}
+
+impl Deltas {
+    /// Get the change of every user gauge. New users change from zero,
+    /// and users that are no longer present are forgotten.
+    fn deltas<'a>(&self, labels: &Labels, values: &[Vec<(&'a UserKey, i64)>]) -> Vec<Vec<(&'a UserKey, i64)>> {
+        let mut prev = self.previous.lock().unwrap();
+        let mut next = HashMap::new();
+        let deltas = values.iter().enumerate().map(|(i, series)| {
+            series.iter().map(|&(key, value)| {
+                let k = (i, labels.with(key).iter().map(|x| x.to_string()).collect());
+                let delta = value - prev.remove(&k).unwrap_or(0);
+                next.insert(k, value);
+                (key, delta)
+            }).collect()
+        }).collect();
+        // keep the other targets' values
+        prev.retain(|(_, values), _| !values.iter().zip(labels.values()).all(|(a, b)| a == b));
+        prev.extend(next);
+        deltas
+    }
+}
+
+/// Processes created and exited per user, by diffing the PIDs of
+/// consecutive scans. A PID that moves to another user counts as an exit
+/// and a creation; one reused between scans is missed.
+struct Churn {
+    created: IntCounterVec,
+    exited: IntCounterVec,
+    // the PIDs of each target's previous scan, keyed by its label values
+    previous: Mutex<HashMap<Vec<String>, HashMap<i32, UserKey>>>
+}
+
+impl Churn {
+    fn observe(&self, labels: &Labels, users: &HashMap<UserKey, ProcEntry>) {
+        let current: HashMap<i32, &UserKey> = users.iter()
+            .flat_map(|(key, entry)| entry.pids.iter().map(move |&pid| (pid, key)))
+            .collect();
+        let mut previous = self.previous.lock().unwrap();
+        let target: Vec<String> = labels.values().iter().map(|x| x.to_string()).collect();
+        // the first scan has nothing to compare to
+        if let Some(prev) = previous.get(&target) {
+            for (pid, key) in &current {
+                if prev.get(pid) != Some(*key) {
+                    self.created.with_label_values(&labels.with(key)).inc();
+                }
+            }
+            for (pid, key) in prev {
+                if current.get(pid) != Some(&key) {
+                    self.exited.with_label_values(&labels.with(key)).inc();
+                }
+            }
+        }
+        previous.insert(target, current.into_iter().map(|(pid, key)| (pid, key.clone())).collect());
+    }
+}
+
+/// All the prometheus metrics, registered on a single registry.
+pub struct Metrics {
+    user_gauges: Vec<UserGauge>,
+    ema: Option<Ema>,
+    // only with --emit-deltas
+    deltas: Option<Deltas>,
+    // only with --count-churn
+    churn: Option<Churn>,
+    // only with --rss-window-seconds
+    rss_window: Option<RssWindow>,
+    pub total_processes: IntGaugeVec,
+    pub total_memory: IntGaugeVec,
+    pub total_swap: IntGaugeVec,
+    pub distinct_users: IntGaugeVec,
+    pub kernel_processes: IntGaugeVec,
+    pub self_memory: IntGaugeVec,
+    pub self_open_fds: IntGaugeVec,
+    pub self_threads: IntGaugeVec,
+    pub self_cpu: CounterVec,
+    pub users_cache_age: GaugeVec,
+    pub unknown_user_processes: IntGaugeVec,
+    pub orphaned_uid: IntGaugeVec,
+    user_sched_class: IntGaugeVec,
+    user_numa_rss: IntGaugeVec,
+    // one per --extra-status-field
+    extra_fields: Vec<IntGaugeVec>,
+    pub tty: GroupGauges,
+    pub loginuser: GroupGauges,
+    pub exe: GroupGauges,
+    pub user_command: GroupGauges,
+    pub scan_failures: IntCounterVec,
+    pub swap_fallbacks: IntCounterVec,
+    pub up: IntGaugeVec,
+    pub scrape_in_progress: IntGaugeVec,
+    pub scrapes: IntCounterVec,
+    pub missing_ids: IntCounterVec,
+    pub empty_scrapes: IntCounterVec,
+    pub scrape_timeouts: IntCounterVec,
+    pub scrape_interval: GaugeVec,
+    // only with --rss-histogram-scope
+    rss_histogram: Option<(RssHistogramScope, HistogramVec)>
+}
+
+/// How the metrics are named, labeled, and reported.
+pub struct MetricsOptions {
+    /// The names of the job, hostgroup, and instance labels.
+    pub label_names: LabelNames,
+    pub unit: MemoryUnit,
+    /// The labels of a `UserKey`, starting with "username".
+    pub user_labels: Vec<&'static str>,
+    /// Smooth per-user gauges, reporting the instantaneous values with a
+    /// `_raw` suffix.
+    pub ema_alpha: Option<f64>,
+    /// Leave out per-user swap series that are zero.
+    pub omit_zero_swap: bool,
+    /// Also report the change of each per-user gauge since the last scan,
+    /// with a `_delta` suffix.
+    pub emit_deltas: bool,
+    /// Extra per-user gauges, as (status field, metric name), summing a
+    /// field from `parse_status_with`.
+    pub extra_status_fields: Vec<(String, String)>,
+    /// Count processes created and exited per user between scans.
+    pub count_churn: bool,
+    /// Also report the largest per-user RSS over this long.
+    pub rss_window: Option<Duration>,
+    /// Observe the RSS of each process or user in a histogram with these
+    /// buckets.
+    pub rss_histogram: Option<(RssHistogramScope, Vec<f64>)>
+}
+
+impl Metrics {
+    /// Register the metrics. The exporter's own proc_mem_to_prom_* metrics
+    /// go on `self_registry`, which may be the same registry.
+    pub fn new(registry: &Registry, self_registry: &Registry, options: &MetricsOptions) -> prometheus::Result<Metrics> {
+        let unit = options.unit;
+        let ema_alpha = options.ema_alpha;
+        let names = &options.label_names;
+        let mut labels = names.to_vec();
+        labels.extend(&options.user_labels);
+        let user_gauge = |name: String, help: String, value: fn(&ProcEntry) -> Option<i64>| {
+            let raw = match ema_alpha {
+                Some(_) => Some(register!(IntGaugeVec, opts!(
+                    format!("{}_raw", name),
+                    format!("{} Not smoothed.", help)),
+                    &labels,
+                    registry
+                )?),
+                None => None
+            };
+            let delta = if options.emit_deltas {
+                Some(register!(IntGaugeVec, opts!(
+                    format!("{}_delta", name),
+                    format!("{} The change since the last scan.", help)),
+                    &labels,
+                    registry
+                )?)
+            } else {
+                None
+            };
+            Ok::<_, prometheus::Error>(UserGauge {
+                gauge: register!(IntGaugeVec, opts!(name, help), &labels, registry)?,
+                raw,
+                delta,
+                value,
+                omit_zero: false,
+                flag: None
+            })
+        };
+        Ok(Metrics {
+            ema: ema_alpha.map(|alpha| Ema{alpha, values: Mutex::new(HashMap::new())}),
+            deltas: options.emit_deltas.then(Deltas::default),
+            rss_window: match options.rss_window {
+                Some(window) => Some(RssWindow {
+                    gauge: register!(IntGaugeVec, opts!(
+                        format!("node_user_processes_rss_max_window{}", unit.suffix()),
+                        format!("The largest RSS on a node per user over the last {:?}, in {}.", window, unit.help())),
+                        &labels,
+                        registry
+                    )?,
+                    window,
+                    samples: Mutex::new(HashMap::new())
+                }),
+                None => None
+            },
+            churn: if options.count_churn {
+                Some(Churn {
+                    created: register!(IntCounterVec, opts!(
+                        "node_user_processes_created_total",
+                        "The number of processes created per user, as seen between scans."),
+                        &labels,
+                        registry
+                    )?,
+                    exited: register!(IntCounterVec, opts!(
+                        "node_user_processes_exited_total",
+                        "The number of processes exited per user, as seen between scans."),
+                        &labels,
+                        registry
+                    )?,
+                    previous: Mutex::new(HashMap::new())
+                })
+            } else {
+                None
+            },
+            user_gauges: vec![
+                user_gauge(
+                    "node_user_processes".to_string(),
+                    "The number of processes per user.".to_string(),
+                    |e| Some(e.count))?,
+                user_gauge(
+                    format!("node_user_processes_rss{}", unit.suffix()),
+                    format!("The RSS on a node per user, in {}.", unit.help()),
+                    |e| Some(e.rss))?,
+                user_gauge(
+                    format!("node_user_processes_rss_shmem{}", unit.suffix()),
+                    format!("The shared memory RSS on a node per user, in {}.", unit.help()),
+                    |e| Some(e.shmem))?,
+                user_gauge(
+                    format!("node_user_processes_swap{}", unit.suffix()),
+                    format!("The swap on a node per user, in {}.", unit.help()),
+                    |e| Some(e.swap)).map(|g| UserGauge{omit_zero: options.omit_zero_swap, ..g})?,
+                user_gauge(
+                    // named with its unit, unlike the older memory gauges
+                    match unit {
+                        MemoryUnit::Bytes => "node_user_processes_locked_bytes",
+                        MemoryUnit::Kib => "node_user_processes_locked_kib"
+                    }.to_string(),
+                    format!("The mlock()ed memory on a node per user, in {}.", unit.help()),
+                    |e| Some(e.locked))?,
+                user_gauge(
+                    match unit {
+                        MemoryUnit::Bytes => "node_user_processes_page_table_bytes",
+                        MemoryUnit::Kib => "node_user_processes_page_table_kib"
+                    }.to_string(),
+                    format!("The page table memory on a node per user, in {}.", unit.help()),
+                    |e| Some(e.page_tables))?,
+                user_gauge(
+                    "node_user_processes_max_threads".to_string(),
+                    "The largest thread count of a single process per user.".to_string(),
+                    |e| Some(e.max_threads))?,
+                user_gauge(
+                    "node_user_processes_max_children".to_string(),
+                    "The most direct children of a single process per user.".to_string(),
+                    |e| Some(e.max_children))?,
+                user_gauge(
+                    "node_user_processes_blocked".to_string(),
+                    "The number of processes in uninterruptible sleep per user.".to_string(),
+                    |e| Some(e.blocked))?,
+                user_gauge(
+                    "node_user_processes_blocked_threads".to_string(),
+                    "The number of threads in uninterruptible sleep per user.".to_string(),
+                    |e| e.blocked_threads).map(|g| UserGauge{flag: Some("--count-threads"), ..g})?,
+                user_gauge(
+                    "node_user_processes_disk_sleep".to_string(),
+                    "The number of processes in one of --disk-sleep-states per user.".to_string(),
+                    |e| Some(e.disk_sleep))?,
+                user_gauge(
+                    "node_user_processes_stopped".to_string(),
+                    "The number of stopped or traced processes per user.".to_string(),
+                    |e| Some(e.stopped))?,
+                user_gauge(
+                    "node_user_processes_rt".to_string(),
+                    "The number of processes with SCHED_FIFO or SCHED_RR scheduling per user.".to_string(),
+                    |e| Some(e.rt))?,
+                user_gauge(
+                    "node_user_processes_over_threshold".to_string(),
+                    "The number of processes with more RSS than --big-process-bytes per user.".to_string(),
+                    |e| e.over_threshold).map(|g| UserGauge{flag: Some("--big-process-bytes"), ..g})?,
+                user_gauge(
+                    "node_user_processes_high_fd".to_string(),
+                    "The number of processes with more open fds than --high-fd-threshold per user.".to_string(),
+                    |e| e.high_fd).map(|g| UserGauge{flag: Some("--high-fd-threshold"), ..g})?,
+                user_gauge(
+                    "node_user_processes_min_nice".to_string(),
+                    "The lowest nice value of a process per user.".to_string(),
+                    |e| e.min_nice)?,
+                user_gauge(
+                    "node_user_processes_max_nice".to_string(),
+                    "The highest nice value of a process per user.".to_string(),
+                    |e| e.max_nice)?
+            ],
+            total_processes: register!(IntGaugeVec, opts!(
+                "node_total_processes",
+                "The number of processes on a node."),
+                names,
+                registry
+            )?,
+            total_memory: register!(IntGaugeVec, opts!(
+                format!("node_total_rss{}", unit.suffix()),
+                format!("The RSS on a node across all users, in {}.", unit.help())),
+                names,
+                registry
+            )?,
+            total_swap: register!(IntGaugeVec, opts!(
+                format!("node_total_swap{}", unit.suffix()),
+                format!("The swap on a node across all users, in {}.", unit.help())),
+                names,
+                registry
+            )?,
+            distinct_users: register!(IntGaugeVec, opts!(
+                "node_distinct_users",
+                "The number of distinct users with processes on a node."),
+                names,
+                registry
+            )?,
+            kernel_processes: register!(IntGaugeVec, opts!(
+                "node_kernel_processes",
+                "The number of processes without a VmRSS, such as kernel threads, left out of the per-user metrics."),
+                names,
+                registry
+            )?,
+            self_memory: register!(IntGaugeVec, opts!(
+                match unit {
+                    MemoryUnit::Bytes => "proc_mem_to_prom_self_rss_bytes",
+                    MemoryUnit::Kib => "proc_mem_to_prom_self_rss_kib"
+                },
+                format!("The RSS of the exporter itself, in {}.", unit.help())),
+                names,
+                self_registry
+            )?,
+            self_open_fds: register!(IntGaugeVec, opts!(
+                "proc_mem_to_prom_self_open_fds",
+                "The number of open file descriptors of the exporter itself."),
+                names,
+                self_registry
+            )?,
+            self_threads: register!(IntGaugeVec, opts!(
+                "proc_mem_to_prom_self_threads",
+                "The number of threads of the exporter itself."),
+                names,
+                self_registry
+            )?,
+            self_cpu: register!(CounterVec, opts!(
+                "proc_mem_to_prom_self_cpu_seconds_total",
+                "The user and system CPU time of the exporter itself."),
+                names,
+                self_registry
+            )?,
+            users_cache_age: register!(GaugeVec, opts!(
+                "proc_mem_to_prom_users_cache_age_seconds",
+                "The time since the user name cache was built."),
+                names,
+                self_registry
+            )?,
+            unknown_user_processes: register!(IntGaugeVec, opts!(
+                "proc_mem_to_prom_unknown_user_processes",
+                "The number of processes counted as unknown users in the last scan, including those with no usable Uid."),
+                names,
+                self_registry
+            )?,
+            user_sched_class: register!(IntGaugeVec, opts!(
+                "node_user_processes_by_sched_class",
+                "The number of processes per user and scheduling class."),
+                &[labels.as_slice(), &["class"]].concat(),
+                registry
+            )?,
+            user_numa_rss: register!(IntGaugeVec, opts!(
+                format!("node_user_processes_rss_by_node{}", unit.suffix()),
+                format!("The resident memory per user on each NUMA node, from numa_maps, in {}.", unit.help())),
+                &[labels.as_slice(), &["numa_node"]].concat(),
+                registry
+            )?,
+            extra_fields: options.extra_status_fields.iter().map(|(field, name)| {
+                register!(IntGaugeVec, opts!(
+                    name,
+                    format!("The sum of {} from /proc/<pid>/status per user.", field)),
+                    &labels,
+                    registry
+                )
+            }).collect::<prometheus::Result<_>>()?,
+            orphaned_uid: register!(IntGaugeVec, opts!(
+                "node_processes_orphaned_uid",
+                "The number of processes per uid with no existing user."),
+                &[names[0], names[1], names[2], "uid"],
+                registry
+            )?,
+            tty: GroupGauges::new(registry, names, "tty", &["tty"], unit)?,
+            loginuser: GroupGauges::new(registry, names, "loginuser", &["loginuser"], unit)?,
+            exe: GroupGauges::new(registry, names, "exe", &["exe"], unit)?,
+            user_command: GroupGauges::new(registry, names, "user_command", &["username", "command"], unit)?,
+            scan_failures: register!(IntCounterVec, opts!(
+                "proc_mem_to_prom_scan_failures_total",
+                "The number of failed attempts to list the processes in /proc."),
+                names,
+                self_registry
+            )?,
+            swap_fallbacks: register!(IntCounterVec, opts!(
+                "proc_mem_to_prom_swap_fallbacks_total",
+                "The number of processes whose swap was read from smaps, lacking VmSwap in status."),
+                names,
+                self_registry
+            )?,
+            up: register!(IntGaugeVec, opts!(
+                "proc_mem_to_prom_up",
+                "Whether the exporter is running and its last scan of /proc worked."),
+                names,
+                self_registry
+            )?,
+            scrape_in_progress: register!(IntGaugeVec, opts!(
+                "proc_mem_to_prom_scrape_in_progress",
+                "Whether a scan of /proc is running."),
+                names,
+                self_registry
+            )?,
+            scrapes: register!(IntCounterVec, opts!(
+                "proc_mem_to_prom_scrapes_total",
+                "The number of scans of /proc started."),
+                names,
+                self_registry
+            )?,
+            missing_ids: register!(IntCounterVec, opts!(
+                "proc_mem_to_prom_missing_uid_total",
+                "The number of processes whose status had no usable Uid or Gid, counted as unknown users."),
+                names,
+                self_registry
+            )?,
+            empty_scrapes: register!(IntCounterVec, opts!(
+                "proc_mem_to_prom_empty_scrapes_total",
+                "The number of scrapes that found no processes at all."),
+                names,
+                self_registry
+            )?,
+            scrape_timeouts: register!(IntCounterVec, opts!(
+                "proc_mem_to_prom_scrape_timeouts_total",
+                "The number of scans of /proc that hit --scrape-timeout."),
+                names,
+                self_registry
+            )?,
+            scrape_interval: register!(GaugeVec, opts!(
+                "proc_mem_to_prom_scrape_interval_seconds",
+                "The configured time between scans of /proc."),
+                names,
+                self_registry
+            )?,
+            rss_histogram: match &options.rss_histogram {
+                Some((scope, buckets)) => Some((*scope, register!(HistogramVec, histogram_opts!(
+                    scope.name(unit),
+                    format!("The RSS of each {}, observed every scan, in {}.", match scope {
+                        RssHistogramScope::Process => "process",
+                        RssHistogramScope::User => "user's processes together"
+                    }, unit.help()),
+                    buckets.clone()),
+                    names,
+                    registry
+                )?)),
+                None => None
+            }
+        })
+    }
+}
+
+impl Metrics {
+    /// Every metric, with the flag without which it has no series.
+    pub fn list(&self) -> Vec<(&dyn Collector, Option<&'static str>)> {
+        let mut list: Vec<(&dyn Collector, Option<&'static str>)> = Vec::new();
+        for g in &self.user_gauges {
+            list.push((&g.gauge, g.flag));
+            if let Some(raw) = &g.raw {
+                list.push((raw, Some("--ema-alpha")));
+            }
+            if let Some(delta) = &g.delta {
+                list.push((delta, Some("--emit-deltas")));
+            }
+        }
+        if let Some(window) = &self.rss_window {
+            list.push((&window.gauge, Some("--rss-window-seconds")));
+        }
+        if let Some(churn) = &self.churn {
+            list.push((&churn.created, Some("--count-churn")));
+            list.push((&churn.exited, Some("--count-churn")));
+        }
+        list.push((&self.total_processes, None));
+        list.push((&self.total_memory, None));
+        list.push((&self.total_swap, None));
+        list.push((&self.distinct_users, None));
+        list.push((&self.kernel_processes, Some("--separate-kernel-memory")));
+        list.push((&self.self_memory, Some("--exclude-self")));
+        list.push((&self.self_open_fds, None));
+        list.push((&self.self_threads, None));
+        list.push((&self.self_cpu, None));
+        list.push((&self.users_cache_age, None));
+        list.push((&self.unknown_user_processes, None));
+        list.push((&self.user_sched_class, None));
+        list.push((&self.user_numa_rss, Some("--numa")));
+        for gauge in &self.extra_fields {
+            list.push((gauge, Some("--extra-status-field")));
+        }
+        list.push((&self.orphaned_uid, None));
+        for (group, flag) in [(&self.tty, "--group-by-tty"), (&self.loginuser, "--group-by-loginuid"), (&self.exe, "--group-by-exe"), (&self.user_command, "--group-by-user-command")] {
+            list.push((&group.processes, Some(flag)));
+            list.push((&group.memory, Some(flag)));
+            list.push((&group.swap, Some(flag)));
+        }
+        list.push((&self.scan_failures, None));
+        list.push((&self.swap_fallbacks, Some("--swap-fallback")));
+        list.push((&self.up, None));
+        list.push((&self.scrape_in_progress, None));
+        list.push((&self.scrapes, None));
+        list.push((&self.missing_ids, None));
+        list.push((&self.empty_scrapes, None));
+        list.push((&self.scrape_timeouts, Some("--scrape-timeout")));
+        list.push((&self.scrape_interval, None));
+        if let Some((_, histogram)) = &self.rss_histogram {
+            list.push((histogram, Some("--rss-histogram-scope")));
+        }
+        list
+    }
+}
+
+/// Observe each process in the RSS histogram, if it is per process.
+pub fn observe_processes(processes: &[ProcInfo], metrics: &Metrics, labels: &Labels, unit: MemoryUnit) {
+    if let Some((RssHistogramScope::Process, histogram)) = &metrics.rss_histogram {
+        let histogram = histogram.with_label_values(&labels.values());
+        for info in processes {
+            histogram.observe(unit.convert(info.status.vmrss) as f64);
+        }
+    }
+}
+
+/// Process count, RSS, and swap gauges for processes grouped by something
+/// other than the user, e.g. `node_tty_processes{tty="pts/3"}`.
+pub struct GroupGauges {
+    pub processes: IntGaugeVec,
+    pub memory: IntGaugeVec,
+    pub swap: IntGaugeVec
+}
+
+impl GroupGauges {
+    pub fn new(registry: &Registry, names: &LabelNames, group: &str, group_labels: &[&str], unit: MemoryUnit) -> prometheus::Result<GroupGauges> {
+        let mut labels = names.to_vec();
+        labels.extend(group_labels);
+        let labels = &labels;
+        let label = group_labels.join(" and ");
+        Ok(GroupGauges {
+            processes: register!(IntGaugeVec, opts!(
+                format!("node_{}_processes", group),
+                format!("The number of processes per {}.", label)),
+                labels,
+                registry
+            )?,
+            memory: register!(IntGaugeVec, opts!(
+                format!("node_{}_processes_rss{}", group, unit.suffix()),
+                format!("The RSS on a node per {}, in {}.", label, unit.help())),
+                labels,
+                registry
+            )?,
+            swap: register!(IntGaugeVec, opts!(
+                format!("node_{}_processes_swap{}", group, unit.suffix()),
+                format!("The swap on a node per {}, in {}.", label, unit.help())),
+                labels,
+                registry
+            )?
+        })
+    }
+
+    fn publish(&self, groups: &HashMap<String, ProcEntry>, labels: &Labels) {
+        self.publish_keyed(groups.iter().map(|(group, entry)| (vec![group.clone()], entry)).collect(), labels);
+    }
+
+    /// Publish groups keyed by the values of every group label, in order.
+    fn publish_keyed(&self, keys: Vec<(Vec<String>, &ProcEntry)>, labels: &Labels) {
+        set_series(&self.processes, labels, keys.iter().map(|(k, e)| (labels.with(k), e.count)));
+        set_series(&self.memory, labels, keys.iter().map(|(k, e)| (labels.with(k), e.rss)));
+        set_series(&self.swap, labels, keys.iter().map(|(k, e)| (labels.with(k), e.swap)));
+    }
+}
+
+/// Get the label values of every series in a gauge, in the order the
+/// labels were declared.
+fn series_values(gauge: &IntGaugeVec) -> HashSet<Vec<String>> {
+    let names = &gauge.desc()[0].variable_labels;
+    let mut values = HashSet::new();
+    for m in &gauge.collect() {
+        for mm in m.get_metric() {
+            values.insert(names.iter().map(|name| {
+                mm.get_label().iter()
+                    .find(|l| l.get_name() == name)
+                    .map(|l| l.get_value().to_string())
+                    .unwrap_or_default()
+            }).collect());
+        }
+    }
+    values
+}
+
+/// Set the series of a gauge, removing any previous series with the same
+/// job/hostgroup/instance that are not among them.
+fn set_series<'a>(gauge: &IntGaugeVec, labels: &Labels, series: impl IntoIterator<Item = (Vec<&'a str>, i64)>) {
+    let mut stale = series_values(gauge);
+    stale.retain(|values| values.iter().zip(labels.values()).all(|(a, b)| a == b));
+    for (values, value) in series {
+        gauge.with_label_values(&values).set(value);
+        stale.remove(&values.iter().map(|x| x.to_string()).collect::<Vec<_>>());
+    }
+    for values in &stale {
+        let values: Vec<&str> = values.iter().map(|x| x.as_str()).collect();
+        let _ = gauge.remove_label_values(&values);
+    }
+}
+
+/// Set the gauges from an aggregation, removing series for users
+/// (or uids) that are no longer present.
+pub fn publish(agg: Aggregate, metrics: &Metrics, labels: &Labels) {
+    let Aggregate{users: user_procs, orphaned_uids, unknown_processes, ttys, loginusers, exes, user_commands} = agg;
+
+    let mut total = ProcEntry::default();
+    for entry in user_procs.values() {
+        total.count += entry.count;
+        total.rss += entry.rss;
+        total.swap += entry.swap;
+    }
+    metrics.total_processes.with_label_values(&labels.values()).set(total.count);
+    metrics.total_memory.with_label_values(&labels.values()).set(total.rss);
+    metrics.total_swap.with_label_values(&labels.values()).set(total.swap);
+    // users are counted once even when split by pid_ns or uid
+    let usernames: HashSet<_> = user_procs.keys().map(|key| &key[0]).collect();
+    metrics.distinct_users.with_label_values(&labels.values()).set(usernames.len() as i64);
+
+    let values: Vec<Vec<_>> = metrics.user_gauges.iter().map(|g| {
+        user_procs.iter()
+            .filter_map(|(key, entry)| Some((key, (g.value)(entry)?)))
+            .filter(|(_, value)| !(g.omit_zero && *value == 0))
+            .collect()
+    }).collect();
+    if let Some(deltas) = &metrics.deltas {
+        for (g, series) in metrics.user_gauges.iter().zip(deltas.deltas(labels, &values)) {
+            if let Some(delta) = &g.delta {
+                set_series(delta, labels, series.into_iter().map(|(key, value)| (labels.with(key), value)));
+            }
+        }
+    }
+    let smoothed = match &metrics.ema {
+        Some(ema) => {
+            for (g, series) in metrics.user_gauges.iter().zip(&values) {
+                if let Some(raw) = &g.raw {
+                    set_series(raw, labels, series.iter().map(|(key, value)| (labels.with(key), *value)));
+                }
+            }
+            ema.smooth(labels, values)
+        },
+        None => values
+    };
+    for (g, series) in metrics.user_gauges.iter().zip(smoothed) {
+        set_series(&g.gauge, labels, series.into_iter().map(|(key, value)| (labels.with(key), value)));
+    }
+
+    if let Some(churn) = &metrics.churn {
+        churn.observe(labels, &user_procs);
+    }
+    if let Some(window) = &metrics.rss_window {
+        window.publish(labels, &user_procs);
+    }
+
+    let classes: Vec<_> = user_procs.iter().flat_map(|(key, entry)| {
+        entry.sched_classes.iter().map(move |(class, count)| ([key.as_slice(), std::slice::from_ref(class)].concat(), *count))
+    }).collect();
+    set_series(&metrics.user_sched_class, labels, classes.iter().map(|(k, count)| (labels.with(k), *count)));
+
+    let numa: Vec<_> = user_procs.iter().flat_map(|(key, entry)| {
+        entry.numa.iter().map(move |(node, rss)| ([key.as_slice(), std::slice::from_ref(node)].concat(), *rss))
+    }).collect();
+    set_series(&metrics.user_numa_rss, labels, numa.iter().map(|(k, rss)| (labels.with(k), *rss)));
+
+    for (i, gauge) in metrics.extra_fields.iter().enumerate() {
+        set_series(gauge, labels, user_procs.iter().map(|(key, entry)| {
+            (labels.with(key), entry.extra_fields.get(i).copied().unwrap_or(0))
+        }));
+    }
+
+    if let Some((RssHistogramScope::User, histogram)) = &metrics.rss_histogram {
+        let histogram = histogram.with_label_values(&labels.values());
+        for entry in user_procs.values() {
+            histogram.observe(entry.rss as f64);
+        }
+    }
+
+    metrics.tty.publish(&ttys, labels);
+    metrics.loginuser.publish(&loginusers, labels);
+    metrics.exe.publish(&exes, labels);
+    metrics.user_command.publish_keyed(user_commands.iter().map(|(key, entry)| (key.clone(), entry)).collect(), labels);
+
+    metrics.unknown_user_processes.with_label_values(&labels.values()).set(unknown_processes);
+    let uids: Vec<_> = orphaned_uids.iter().map(|(uid, count)| ([uid.clone()], *count)).collect();
+    set_series(&metrics.orphaned_uid, labels, uids.iter().map(|(k, count)| (labels.with(k), *count)));
+}
+
+#[cfg(test)]
+mod fixtures;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+
+    fn proc_info(fixture: Fixture) -> ProcInfo {
+        ProcInfo::from(parse_status(fixture.status().as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn garbled_uid_is_missing() {
+        let fixture = Fixture{uid: "0\t0\t0".to_string(), ..Fixture::new(7, 100)};
+        let status = parse_status(fixture.status().as_bytes()).unwrap();
+        assert_eq!(status.euid, ID_MISSING);
+        assert_eq!(status.egid, 100);
+        assert_eq!(status.vmrss, Some(1024));
+    }
+
+    #[test]
+    fn missing_uid_is_unknown_but_not_orphaned() {
+        let agg = aggregate(&UsersCache::new(), &[proc_info(Fixture{uid: "garbage".to_string(), ..Fixture::new(7, 0)})], MemoryUnit::Bytes);
+        assert!(agg.orphaned_uids.is_empty());
+        assert_eq!(agg.unknown_processes, 1);
+        assert_eq!(agg.users[&vec!["unknown".to_string()]].count, 1);
+    }
+
+    #[test]
+    fn attributed_by_effective_uid() {
+        // a setuid-root helper started by another user
+        let agg = aggregate(&UsersCache::new(), &[proc_info(Fixture{uid: "54321\t0\t0\t0".to_string(), ..Fixture::new(7, 0)})], MemoryUnit::Bytes);
+        assert_eq!(agg.users.len(), 1);
+        assert_eq!(agg.users[&vec!["root".to_string()]].rss, 1024 * 1024);
+    }
+
+    #[test]
+    fn sanitized_username() {
+        assert_eq!(sanitize_username("  Jane Doe\t"), "Jane_Doe");
+        assert_eq!(sanitize_username("alice"), "alice");
+    }
+
+    #[test]
+    fn merge_sums_counts_and_keeps_maxima() {
+        let mut a = ProcEntry{count: 2, rss: 100, max_threads: 4, min_nice: Some(0), ..Default::default()};
+        let b = ProcEntry{count: 1, rss: 50, max_threads: 8, min_nice: Some(-5), blocked_threads: Some(3), ..Default::default()};
+        a.merge(b);
+        assert_eq!(a.count, 3);
+        assert_eq!(a.rss, 150);
+        assert_eq!(a.max_threads, 8);
+        assert_eq!(a.min_nice, Some(-5));
+        assert_eq!(a.blocked_threads, Some(3));
+    }
+
+    #[test]
+    fn top_n_folds_whole_entries() {
+        let groups = HashMap::from([
+            ("a", ProcEntry{count: 1, rss: 300, ..Default::default()}),
+            ("b", ProcEntry{count: 2, rss: 200, shmem: 10, stopped: 1, ..Default::default()}),
+            ("c", ProcEntry{count: 3, rss: 100, shmem: 5, min_nice: Some(19), ..Default::default()})
+        ]);
+        let groups = top_n(groups, 1, "other");
+        assert_eq!(groups.len(), 2);
+        let other = &groups["other"];
+        assert_eq!((other.count, other.rss, other.shmem, other.stopped), (5, 300, 15, 1));
+        assert_eq!(other.min_nice, Some(19));
+    }
+
+    #[test]
+    fn children_count_includes_filtered_processes() {
+        // a parent whose children were all left out of the scan
+        let children = count_children([7, 7, 7, 1]);
+        let agg = aggregate_with_children(&UsersCache::new(), &[proc_info(Fixture::new(7, 0))], &children, MemoryUnit::Bytes);
+        assert_eq!(agg.users[&vec!["root".to_string()]].max_children, 3);
+    }
+}
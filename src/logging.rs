@@ -0,0 +1,60 @@
+//! Where log lines go: plain stderr lines, the systemd journal, or JSON
+//! lines for log shippers.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use systemd_journal_logger::JournalLog;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Just the message, as before there were log formats
+    Plain,
+    /// Native journal entries with priorities
+    Journal,
+    /// One JSON object per line
+    Json
+}
+
+struct StderrLog {
+    format: LogFormat
+}
+
+impl Log for StderrLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Json => json!({
+                "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string()
+            }).to_string(),
+            _ => record.args().to_string()
+        };
+        let _ = writeln!(std::io::stderr(), "{}", line);
+    }
+
+    fn flush(&self) { }
+}
+
+/// Install the logger for the format, logging only up to `level`.
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<(), String> {
+    match format {
+        LogFormat::Journal => JournalLog::new()
+            .map_err(|e| format!("cannot connect to the journal: {}", e))?
+            .install()
+            .map_err(|e| e.to_string())?,
+        _ => log::set_boxed_logger(Box::new(StderrLog{format})).map_err(|e| e.to_string())?
+    }
+    log::set_max_level(level);
+    Ok(())
+}
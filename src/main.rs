@@ -1,183 +1,1842 @@
+#[cfg(test)]
+mod fixtures;
+mod graphite;
+mod logging;
+mod proxy_protocol;
+mod remote_write;
+
 use clap::Parser;
 use hyper::{
-    header::CONTENT_TYPE,
+    body::Bytes,
+    header::{HeaderValue, ACCEPT, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_LENGTH, CONTENT_TYPE},
+    server::conn::{AddrStream, Http},
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server
+    Body, Method, Request, Response, Server
 };
-use lazy_static::lazy_static;
-use procfs::process::{all_processes, Status};
+use procfs::process::{all_processes_with_root, Process};
 use procfs::ProcError;
-use prometheus::{Encoder, IntGaugeVec, TextEncoder};
-use prometheus::{opts, register_int_gauge_vec};
-use prometheus::core::Collector;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use graphite::Graphite;
+use log::{debug, error, info, warn, LevelFilter};
+use logging::LogFormat;
+use remote_write::RemoteWrite;
+use proc_mem_to_prom::clock::ticks_to_seconds;
+use proc_mem_to_prom::{aggregate_with_children, count_children, default_rss_buckets, observe_processes, parse_status_with, probe_status_field, publish, sanitize_username, sched_class_name, top_n, tty_name, username_for, Aggregate, LabelNames, Labels, MemoryUnit, Metrics, MetricsOptions, ProcEntry, ProcInfo, RssHistogramScope, UserKey, DEFAULT_LABEL_NAMES, ID_MISSING, LOGINUID_UNSET};
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{Encoder, Histogram, ProtobufEncoder, IntCounter, IntCounterVec, Registry, TextEncoder};
+use prometheus::{histogram_opts, opts, register_histogram_vec_with_registry, register_int_counter_vec_with_registry};
+use serde::Serialize;
+use serde_json::json;
+use socket2::{Domain, Socket, Type};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use tokio::time::{sleep, Duration, Instant};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration, Instant};
 use users::{Users, UsersCache};
 
-// declare all the prometheus metrics
-lazy_static! {
-    static ref USER_PROCESSES_GAUGE: IntGaugeVec = register_int_gauge_vec!(opts!(
-        "node_user_processes",
-        "The number of processes per user."),
-        &["job", "hostgroup", "instance", "username"]
-    )
-    .unwrap();
-    static ref USER_MEMORY_GAUGE: IntGaugeVec = register_int_gauge_vec!(opts!(
-        "node_user_processes_rss",
-        "The RSS on a node per user."),
-        &["job", "hostgroup", "instance", "username"]
-    )
-    .unwrap();
-    static ref USER_SWAP_GAUGE: IntGaugeVec = register_int_gauge_vec!(opts!(
-        "node_user_processes_swap",
-        "The swap on a node per user."),
-        &["job", "hostgroup", "instance", "username"]
-    )
-    .unwrap();
-}
-
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Serialize)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(long, default_value_t = false)]
     oneshot: bool,
 
-    #[arg(short, long)]
-    port: Option<u16>,
+    /// Like --oneshot, but keep serving (or pushing) afterwards
+    #[arg(long, default_value_t = false)]
+    serve_after_oneshot: bool,
+
+    /// Scrape once, explain which processes were included or excluded, and exit
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Leave the exporter's own process out of the per-user metrics
+    #[arg(long, default_value_t = false)]
+    exclude_self: bool,
+
+    /// Also count blocked threads by reading every task's stat (expensive)
+    #[arg(long, default_value_t = false)]
+    count_threads: bool,
+
+    /// Also aggregate by controlling terminal
+    #[arg(long, default_value_t = false)]
+    group_by_tty: bool,
+
+    /// Report at most this many ttys, folding the rest into "other"
+    #[arg(long, default_value_t = 20)]
+    tty_top_n: usize,
+
+    /// Trim usernames and replace whitespace inside them with underscores
+    #[arg(long, default_value_t = false)]
+    sanitize_usernames: bool,
+
+    /// Count processes without a VmRSS, such as kernel threads, in
+    /// node_kernel_processes instead of the per-user metrics
+    #[arg(long, default_value_t = false)]
+    separate_kernel_memory: bool,
+
+    /// Print only the metrics with --oneshot, and log only warnings and
+    /// errors
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+
+    /// Log the users with the most RSS after each scan, and debug
+    /// messages such as one line per HTTP request
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+
+    /// Also aggregate by the user who originally logged in (audit loginuid)
+    #[arg(long, default_value_t = false)]
+    group_by_loginuid: bool,
+
+    /// Also aggregate by executable path
+    #[arg(long, default_value_t = false)]
+    group_by_exe: bool,
+
+    /// Also report each user's resident memory per NUMA node, read from
+    /// every process's numa_maps, which is slow on large machines
+    #[arg(long, default_value_t = false)]
+    numa: bool,
+
+    /// Also aggregate by user and command name together
+    #[arg(long, default_value_t = false)]
+    group_by_user_command: bool,
+
+    /// Report at most this many user and command pairs, folding the rest
+    /// into "other"
+    #[arg(long, default_value_t = 20)]
+    user_command_top_n: usize,
+
+    /// Label per-user series with pid_ns="root" or "child", by whether
+    /// the process shares the exporter's pid namespace
+    #[arg(long, default_value_t = false)]
+    detect_containers: bool,
+
+    /// Label per-user series with the numeric euid as uid, as well as
+    /// the username
+    #[arg(long, default_value_t = false)]
+    include_uid_label: bool,
+
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Serve HTTP on this address, e.g. 127.0.0.1:9100 or [::]:9100,
+    /// instead of all IPv4 addresses on --port; can be repeated to
+    /// serve the same metrics on several sockets
+    #[arg(long)]
+    listen: Vec<SocketAddr>,
+
+    /// Write the metrics to this file each interval instead of serving HTTP
+    #[arg(long, conflicts_with_all = ["remote_write", "graphite"])]
+    textfile: Option<PathBuf>,
+
+    /// Push the metrics to this remote-write URL each interval instead of serving HTTP
+    #[arg(long, conflicts_with = "graphite")]
+    remote_write: Option<String>,
+
+    /// Authorization header value to send with remote-write requests
+    #[arg(long)]
+    #[serde(serialize_with = "redact")]
+    remote_write_auth: Option<String>,
+
+    /// Send the metrics to this Graphite host:port each interval instead of serving HTTP
+    #[arg(long)]
+    graphite: Option<String>,
+
+    /// Send Access-Control-Allow-Origin with this origin and answer CORS preflights
+    #[arg(long, value_parser = parse_origin)]
+    cors_allow_origin: Option<String>,
+
+    /// Serve debugging endpoints: /debug/processes, /config with the
+    /// effective configuration, and /metadata with each metric's type and help
+    #[arg(long, default_value_t = false)]
+    enable_debug_endpoints: bool,
+
+    /// The unit to report memory in
+    #[arg(long, value_enum, default_value_t = MemoryUnit::Bytes)]
+    memory_unit: MemoryUnit,
+
+    /// Seconds to reuse an encoded /metrics response for (0 disables caching)
+    #[arg(long, value_parser = parse_seconds_or_zero, default_value_t = 1.0)]
+    cache_ttl: f64,
+
+    /// Answer 500 rather than send a /metrics response larger than this
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    max_response_bytes: usize,
+
+    /// Smooth per-user gauges with an exponential moving average of this
+    /// weight (0 < alpha <= 1), also reporting the raw values as *_raw
+    #[arg(long, value_parser = parse_ema_alpha)]
+    ema_alpha: Option<f64>,
+
+    /// Exit instead of sanitizing job, group, or instance values with
+    /// characters outside [A-Za-z0-9_.:-]
+    #[arg(long, default_value_t = false)]
+    strict_labels: bool,
+
+    /// Give up on a scan of /proc after this many seconds, reporting the
+    /// processes read so far
+    #[arg(long, value_parser = parse_seconds)]
+    scrape_timeout: Option<f64>,
+
+    /// Scan this procfs instead of /proc, as root=<path>[,instance=<name>][,group=<name>];
+    /// repeat it to scan several, adding /proc as a target to keep it
+    #[arg(long, value_parser = parse_target)]
+    target: Vec<TargetArg>,
+
+    /// The state characters counted by node_user_processes_disk_sleep
+    #[arg(long, default_value = "D")]
+    disk_sleep_states: String,
+
+    /// Add a constant label to every series, as key=value (repeatable);
+    /// also read from PROM_LABEL_<KEY>=<value> environment variables,
+    /// with <KEY> lowercased, so PROM_LABEL_POD_NAME sets pod_name
+    #[arg(long, value_parser = parse_label)]
+    label: Vec<(String, String)>,
+
+    /// Print every metric with its help, labels, and enabling flag, then exit
+    #[arg(long, default_value_t = false)]
+    list_metrics: bool,
+
+    /// Observe the RSS of each process, or each user's total, in a histogram
+    #[arg(long, value_enum)]
+    rss_histogram_scope: Option<RssHistogramScope>,
+
+    /// The RSS histogram bucket boundaries, in --memory-unit
+    /// (default 1 MiB to 256 GiB by 4x)
+    #[arg(long, value_delimiter = ',')]
+    rss_histogram_buckets: Vec<f64>,
+
+    /// Read at most this many processes per second, so scans of huge
+    /// hosts take longer but add less load (default unlimited)
+    #[arg(long, value_parser = parse_read_rate)]
+    max_procfs_read_rate: Option<f64>,
+
+    /// Count each user's processes with more RSS than this many bytes
+    #[arg(long)]
+    big_process_bytes: Option<u64>,
+
+    /// Count each user's processes with more open fds than this
+    #[arg(long)]
+    high_fd_threshold: Option<usize>,
+
+    /// Only count this process and its descendants
+    #[arg(long)]
+    pid_subtree: Option<i32>,
+
+    /// Only count processes with at least this euid, e.g. 1000 to leave
+    /// out system accounts
+    #[arg(long)]
+    uid_min: Option<u32>,
+
+    /// Only count processes with at most this euid
+    #[arg(long)]
+    uid_max: Option<u32>,
+
+    /// Leave out processes started less than this many seconds ago.
+    /// Processes whose age can't be read are kept
+    #[arg(long)]
+    min_process_age: Option<f64>,
+
+    /// Sum the swap from smaps for processes whose status has no VmSwap,
+    /// as on some kernels
+    #[arg(long, default_value_t = false)]
+    swap_fallback: bool,
+
+    /// Set SO_REUSEADDR on the HTTP listener, to restart without waiting
+    /// out TIME_WAIT
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    reuse_addr: bool,
+
+    /// Set SO_REUSEPORT on the HTTP listener, so two instances can share
+    /// the port during a restart
+    #[arg(long, default_value_t = false)]
+    reuse_port: bool,
+
+    /// Expect a PROXY protocol (v1 or v2) header on each HTTP connection,
+    /// as sent by an L4 load balancer
+    #[arg(long, default_value_t = false)]
+    proxy_protocol: bool,
+
+    /// Also report the change of each per-user gauge since the last scan,
+    /// as *_delta
+    #[arg(long, default_value_t = false)]
+    emit_deltas: bool,
+
+    /// Serve only our own metrics, leaving out any collectors the
+    /// prometheus crate registers by default (such as process_*)
+    #[arg(long, default_value_t = false)]
+    no_default_collectors: bool,
+
+    /// With --remote-write or --graphite, leave out gauges whose value
+    /// hasn't changed since they were last sent
+    #[arg(long, default_value_t = false)]
+    emit_changed_only: bool,
+
+    /// Put the time of encoding on every sample, for backfills; live
+    /// scrapes shouldn't need it, and node_exporter's textfile collector
+    /// rejects files with timestamps, so not with --textfile
+    #[arg(long, default_value_t = false, conflicts_with = "textfile")]
+    emit_timestamps: bool,
+
+    /// On SIGTERM, scan once more and emit the result before exiting, so
+    /// pushed metrics keep the last state of a draining node; in HTTP mode
+    /// the result is served for --flush-grace first
+    #[arg(long, default_value_t = false)]
+    flush_on_sigterm: bool,
+
+    /// Seconds to keep serving HTTP after the --flush-on-sigterm scan, so
+    /// a last scrape can read it
+    #[arg(long, value_parser = parse_seconds, default_value_t = 15.0)]
+    flush_grace: f64,
+
+    /// Count processes created and exited per user, by comparing the PIDs
+    /// of consecutive scans, as node_user_processes_{created,exited}_total
+    #[arg(long, default_value_t = false)]
+    count_churn: bool,
+
+    /// Also report the largest RSS per user over this many seconds, as
+    /// node_user_processes_rss_max_window
+    #[arg(long, value_parser = parse_seconds)]
+    rss_window_seconds: Option<f64>,
+
+    /// How to write log lines
+    #[arg(long, value_enum, default_value_t = LogFormat::Plain)]
+    log_format: LogFormat,
+
+    /// Sum another numeric status field per user, as Field:metric_name
+    /// (repeatable or comma-separated), e.g. VmLck:node_user_processes_vmlck
+    #[arg(long, value_delimiter = ',', value_parser = parse_extra_field)]
+    extra_status_field: Vec<(String, String)>,
+
+    /// Leave out per-user swap series that are zero
+    #[arg(long, default_value_t = false)]
+    omit_zero_swap: bool,
+
+    /// Serve the exporter's own proc_mem_to_prom_* metrics on this path
+    /// instead of with the rest on /metrics
+    #[arg(long)]
+    self_metrics_path: Option<String>,
+
+    #[arg(long)]
+    job: Option<String>,
+
+    #[arg(long)]
+    group: Option<String>,
+    
+    #[arg(long)]
+    instance: Option<String>,
+
+    /// Name the job label this instead
+    #[arg(long)]
+    label_name_job: Option<String>,
+
+    /// Name the hostgroup label this instead
+    #[arg(long)]
+    label_name_hostgroup: Option<String>,
+
+    /// Name the instance label this instead, e.g. node
+    #[arg(long)]
+    label_name_instance: Option<String>,
+
+    /// Without --instance or INSTANCE, use the fully-qualified hostname as
+    /// the instance, or the short hostname if it doesn't resolve
+    #[arg(long, default_value_t = false)]
+    instance_fqdn: bool
+}
+
+/// The short hostname.
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0)?;
+    String::from_utf8(buf[..len].to_vec()).ok().filter(|host| !host.is_empty())
+}
+
+/// The canonical name of a host, from a forward lookup.
+fn fqdn(host: &str) -> Option<String> {
+    let node = std::ffi::CString::new(host).ok()?;
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    let mut res = std::ptr::null_mut();
+    if unsafe { libc::getaddrinfo(node.as_ptr(), std::ptr::null(), &hints, &mut res) } != 0 {
+        return None;
+    }
+    let name = unsafe {
+        let canon = (*res).ai_canonname;
+        let name = (!canon.is_null()).then(|| std::ffi::CStr::from_ptr(canon).to_string_lossy().into_owned());
+        libc::freeaddrinfo(res);
+        name
+    };
+    name.filter(|name| !name.is_empty())
+}
+
+/// A process that was skipped during the scan, and why.
+struct Excluded {
+    pid: Option<i32>,
+    // if the status was read, so its children are still counted
+    ppid: Option<i32>,
+    reason: String
+}
+
+/// Serialize a secret as whether it is set.
+fn redact<S: serde::Serializer>(secret: &Option<String>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(match secret {
+        Some(_) => "<redacted>",
+        None => "<unset>"
+    })
+}
+
+fn parse_ema_alpha(s: &str) -> Result<f64, String> {
+    let alpha: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if alpha > 0.0 && alpha <= 1.0 {
+        Ok(alpha)
+    } else {
+        Err(format!("{} is not in (0, 1]", alpha))
+    }
+}
+
+// longer than anything should take, and short enough to add to an Instant
+const MAX_SECONDS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+// a number of seconds that Duration::from_secs_f64 takes
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if seconds > 0.0 && seconds <= MAX_SECONDS {
+        Ok(seconds)
+    } else {
+        Err(format!("{} is not a number of seconds in (0, {}]", seconds, MAX_SECONDS))
+    }
+}
+
+// like parse_seconds, but 0 to disable
+fn parse_seconds_or_zero(s: &str) -> Result<f64, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if seconds == 0.0 {
+        return Ok(0.0);
+    }
+    parse_seconds(s).map_err(|_| format!("{} is not a number of seconds in [0, {}]", seconds, MAX_SECONDS))
+}
+
+// slower than this, a scan would take hours even on a small host
+const MIN_READ_RATE: f64 = 1.0;
+
+fn parse_read_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if rate >= MIN_READ_RATE && rate.is_finite() {
+        Ok(rate)
+    } else {
+        Err(format!("{} is not a finite rate of at least {} processes per second", rate, MIN_READ_RATE))
+    }
+}
+
+// an origin has to be sendable as a header value
+fn parse_origin(s: &str) -> Result<String, String> {
+    match HeaderValue::from_str(s) {
+        Ok(_) => Ok(s.to_string()),
+        Err(_) => Err(format!("{:?} is not a valid header value", s))
+    }
+}
+
+/// A --target argument, before the defaults are filled in.
+#[derive(Clone, Debug, Serialize)]
+struct TargetArg {
+    root: PathBuf,
+    instance: Option<String>,
+    group: Option<String>
+}
+
+fn parse_target(s: &str) -> Result<TargetArg, String> {
+    let mut target = TargetArg{root: PathBuf::new(), instance: None, group: None};
+    for part in s.split(',') {
+        match part.split_once('=') {
+            Some(("root", v)) => target.root = PathBuf::from(v),
+            Some(("instance", v)) => target.instance = Some(v.to_string()),
+            Some(("group", v)) => target.group = Some(v.to_string()),
+            _ => return Err(format!("unknown target option {:?}", part))
+        }
+    }
+    if target.root.as_os_str().is_empty() {
+        return Err("target has no root".to_string());
+    }
+    Ok(target)
+}
+
+/// A procfs to scan, and the label values for its series.
+#[derive(Clone)]
+struct Target {
+    labels: [String; 3],
+    scan: ScanOptions
+}
+
+impl Target {
+    fn labels(&self) -> Labels<'_> {
+        Labels {
+            job: &self.labels[0],
+            hostgroup: &self.labels[1],
+            instance: &self.labels[2]
+        }
+    }
+}
+
+fn parse_extra_field(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((field, name)) if !field.is_empty() && !name.is_empty() => Ok((field.to_string(), name.to_string())),
+        _ => Err(format!("{:?} is not Field:metric_name", s))
+    }
+}
+
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) => Ok((k.to_string(), v.to_string())),
+        None => Err(format!("{:?} is not key=value", s))
+    }
+}
+
+// label names our own series use, which a constant label would duplicate;
+// the first three can be renamed
+const RESERVED_LABELS: &[&str] = &[
+    "job", "hostgroup", "instance", "username", "pid_ns", "uid", "tty", "loginuser", "exe", "command", "class", "numa_node", "code", "le"
+];
+
+fn valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
+
+/// Get the names of the job, hostgroup, and instance labels, after any
+/// --label-name-* renames.
+fn label_names(args: &Args) -> Result<LabelNames, String> {
+    let renames = [&args.label_name_job, &args.label_name_hostgroup, &args.label_name_instance];
+    let mut names = DEFAULT_LABEL_NAMES;
+    for (name, rename) in names.iter_mut().zip(renames) {
+        if let Some(rename) = rename {
+            if !valid_label_name(rename) {
+                return Err(format!("invalid label name {:?}", rename));
+            }
+            if RESERVED_LABELS[3..].contains(&rename.as_str()) {
+                return Err(format!("label name {:?} is already used", rename));
+            }
+            // registration needs the names for as long as the metrics live
+            *name = Box::leak(rename.clone().into_boxed_str());
+        }
+    }
+    if names[0] == names[1] || names[0] == names[2] || names[1] == names[2] {
+        return Err(format!("label names {:?} are not distinct", names));
+    }
+    Ok(names)
+}
+
+/// Collect the constant labels, from PROM_LABEL_* variables and then
+/// --label, so the command line wins.
+fn const_labels(args: &Args, names: &LabelNames) -> Result<HashMap<String, String>, String> {
+    let mut labels = HashMap::new();
+    for (k, v) in env::vars() {
+        if let Some(key) = k.strip_prefix("PROM_LABEL_") {
+            // variable names are conventionally upper case, label names not
+            labels.insert(key.to_lowercase(), v);
+        }
+    }
+    labels.extend(args.label.iter().cloned());
+    for (k, v) in labels.iter_mut() {
+        if !valid_label_name(k) {
+            return Err(format!("invalid label name {:?}", k));
+        }
+        if RESERVED_LABELS[3..].contains(&k.as_str()) || names.contains(&k.as_str()) {
+            return Err(format!("label name {:?} is already used", k));
+        }
+        *v = check_label(k, v, args.strict_labels)?;
+    }
+    Ok(labels)
+}
+
+/// Check a label value for characters that are awkward in series and
+/// alert templates, replacing them with '_' unless strict.
+fn check_label(name: &str, value: &str, strict: bool) -> Result<String, String> {
+    let sanitized: String = value.chars().map(|c| match c {
+        'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '.' | ':' | '-' => c,
+        _ => '_'
+    }).collect();
+    if value.is_empty() {
+        return Err(format!("{} label is empty", name));
+    }
+    if sanitized != value {
+        if strict {
+            return Err(format!("invalid {} label {:?}", name, value));
+        }
+        warn!("sanitizing {} label {:?} to {:?}", name, value, sanitized);
+    }
+    Ok(sanitized)
+}
+
+/// What to read for each process, and which processes to leave out.
+#[derive(Clone)]
+struct ScanOptions {
+    exclude_self: bool,
+    count_threads: bool,
+    group_by_tty: bool,
+    tty_top_n: usize,
+    verbose: bool,
+    sanitize_usernames: bool,
+    separate_kernel_memory: bool,
+    group_by_loginuid: bool,
+    detect_containers: bool,
+    include_uid_label: bool,
+    group_by_exe: bool,
+    group_by_user_command: bool,
+    user_command_top_n: usize,
+    numa: bool,
+    scrape_timeout: Option<Duration>,
+    root: PathBuf,
+    disk_sleep_states: Vec<char>,
+    max_read_rate: Option<f64>,
+    big_process_bytes: Option<u64>,
+    high_fd_threshold: Option<usize>,
+    min_process_age: Option<f64>,
+    uid_range: RangeInclusive<u32>,
+    pid_subtree: Option<i32>,
+    swap_fallback: bool,
+    extra_fields: Vec<String>,
+    // the scrape waiting for this scan, if any; the walk stops once it is
+    // dropped, as run_forever's is on shutdown
+    scrape: Option<Weak<()>>
+}
+
+impl ScanOptions {
+    fn from_args(args: &Args) -> ScanOptions {
+        ScanOptions {
+            exclude_self: args.exclude_self,
+            count_threads: args.count_threads,
+            group_by_tty: args.group_by_tty,
+            tty_top_n: args.tty_top_n,
+            verbose: args.verbose,
+            sanitize_usernames: args.sanitize_usernames,
+            separate_kernel_memory: args.separate_kernel_memory,
+            group_by_loginuid: args.group_by_loginuid,
+            detect_containers: args.detect_containers,
+            include_uid_label: args.include_uid_label,
+            group_by_exe: args.group_by_exe,
+            group_by_user_command: args.group_by_user_command,
+            user_command_top_n: args.user_command_top_n,
+            numa: args.numa,
+            scrape_timeout: args.scrape_timeout.map(Duration::from_secs_f64),
+            root: PathBuf::from("/proc"),
+            disk_sleep_states: args.disk_sleep_states.chars().collect(),
+            max_read_rate: args.max_procfs_read_rate,
+            big_process_bytes: args.big_process_bytes,
+            high_fd_threshold: args.high_fd_threshold,
+            min_process_age: args.min_process_age.filter(|age| *age > 0.0),
+            swap_fallback: args.swap_fallback,
+            pid_subtree: args.pid_subtree,
+            uid_range: args.uid_min.unwrap_or(0)..=args.uid_max.unwrap_or(u32::MAX),
+            extra_fields: args.extra_status_field.iter().map(|(field, _)| field.clone()).collect(),
+            scrape: None
+        }
+    }
+
+    /// Whether the scrape this scan is for has gone away.
+    fn abandoned(&self) -> bool {
+        self.scrape.as_ref().is_some_and(|scrape| scrape.strong_count() == 0)
+    }
+
+    /// The labels of a `UserKey` for these options.
+    fn user_labels(&self) -> Vec<&'static str> {
+        let mut labels = vec!["username"];
+        if self.detect_containers {
+            labels.push("pid_ns");
+        }
+        if self.include_uid_label {
+            labels.push("uid");
+        }
+        labels
+    }
+}
+
+/// The inode of a process's pid namespace.
+fn pid_ns_inode(p: &Process) -> Option<u64> {
+    p.namespaces().ok()?.get(std::ffi::OsStr::new("pid")).map(|ns| ns.identifier)
+}
+
+/// Count the threads of a process in uninterruptible sleep.
+/// Only the task states are read: the threads share the leader's memory,
+/// which is counted once from the leader's own status.
+fn count_blocked_threads(p: &Process) -> Option<i64> {
+    let tasks = p.tasks().ok()?;
+    Some(tasks
+        .filter_map(|t| t.and_then(|t| t.stat()).ok())
+        .filter(|stat| stat.state == 'D')
+        .count() as i64)
+}
+
+fn scan_procs(scan: &ScanOptions) -> Result<(Vec<ProcInfo>, Vec<Excluded>), ProcError> {
+    let mut included = Vec::new();
+    let mut excluded = Vec::new();
+    walk_procs(scan, |v| {
+        match v {
+            Ok(info) => included.push(info),
+            Err(e) => excluded.push(e)
+        }
+        true
+    })?;
+    Ok((included, excluded))
+}
+
+/// Sum the Swap lines of smaps_rollup, or of smaps on kernels before 4.14.
+/// None if there are none, as for kernel threads.
+fn smaps_swap(p: &Process) -> Option<u64> {
+    let file = p.open_relative("smaps_rollup").or_else(|_| p.open_relative("smaps")).ok()?;
+    let swap: Vec<u64> = io::read_to_string(file).ok()?.lines()
+        .filter_map(|line| line.strip_prefix("Swap:"))
+        .filter_map(|kib| kib.trim().trim_end_matches("kB").trim().parse().ok())
+        .collect();
+    if swap.is_empty() {
+        None
+    } else {
+        Some(swap.iter().sum())
+    }
+}
+
+/// Sum the resident pages on each NUMA node over the mappings in
+/// numa_maps, in KiB. Empty if unreadable, or without NUMA support.
+fn numa_kib(p: &Process) -> HashMap<String, u64> {
+    let mut nodes = HashMap::new();
+    let maps = match p.open_relative("numa_maps").ok().and_then(|file| io::read_to_string(file).ok()) {
+        Some(x) => x,
+        None => return nodes
+    };
+    for line in maps.lines() {
+        let fields: Vec<_> = line.split_whitespace().filter_map(|field| field.split_once('=')).collect();
+        let page_kib = fields.iter()
+            .find(|(key, _)| *key == "kernelpagesize_kB")
+            .and_then(|(_, kib)| kib.parse::<u64>().ok())
+            .unwrap_or(4);
+        for (key, pages) in fields {
+            if let (Some(node), Ok(pages)) = (key.strip_prefix('N'), pages.parse::<u64>()) {
+                *nodes.entry(node.to_string()).or_insert(0) += pages * page_kib;
+            }
+        }
+    }
+    nodes
+}
+
+/// Seconds since boot, from <root>/uptime.
+fn read_uptime(root: &Path) -> Option<f64> {
+    fs::read_to_string(root.join("uptime")).ok()?.split_whitespace().next()?.parse().ok()
+}
+
+// the longest a paced walk sleeps between checks for an abandoned scan
+const PACE_SLEEP: Duration = Duration::from_millis(100);
+
+/// Read each process, passing it to `f` as soon as it is read.
+/// The walk stops early if `f` returns false.
+fn walk_procs(scan: &ScanOptions, mut f: impl FnMut(Result<ProcInfo, Excluded>) -> bool) -> Result<(), ProcError> {
+    let self_pid = std::process::id() as i32;
+    let self_pid_ns = if scan.detect_containers {
+        Process::myself().ok().as_ref().and_then(pid_ns_inode)
+    } else {
+        None
+    };
+    // starttime counts from boot too, so no btime is needed
+    let uptime = scan.min_process_age.and_then(|_| read_uptime(&scan.root));
+    let start = Instant::now();
+    for (i, v) in all_processes_with_root(&scan.root)?.enumerate() {
+        if let Some(rate) = scan.max_read_rate {
+            // pace the reads evenly rather than in bursts, in short sleeps
+            // so an abandoned scan stops soon
+            let due = start + Duration::from_secs_f64(i as f64 / rate);
+            while !scan.abandoned() && Instant::now() < due {
+                std::thread::sleep(due.saturating_duration_since(Instant::now()).min(PACE_SLEEP));
+            }
+        }
+        if scan.abandoned() {
+            break;
+        }
+        let p = match v {
+            Ok(p) => p,
+            Err(e) => {
+                if !f(Err(Excluded{pid: None, ppid: None, reason: format!("cannot read process: {}", e)})) {
+                    break;
+                }
+                continue;
+            }
+        };
+        if scan.exclude_self && scan.root == Path::new("/proc") && p.pid == self_pid {
+            if !f(Err(Excluded{pid: Some(p.pid), ppid: None, reason: "exporter's own process".to_string()})) {
+                break;
+            }
+            continue;
+        }
+        // the uid and memory come from this one read, so they always agree;
+        // /proc lists only thread-group leaders, so memory is never summed
+        // per thread
+        let v = match p.open_relative("status").and_then(|f| parse_status_with(f, &scan.extra_fields)) {
+            Ok((status, _)) if !scan.uid_range.contains(&status.euid) => {
+                Err(Excluded{pid: Some(p.pid), ppid: Some(status.ppid), reason: format!("euid {} outside --uid-min/--uid-max", status.euid)})
+            },
+            Ok((mut status, extra_fields)) => {
+                let disk_sleep = status.state.chars().next().is_some_and(|c| scan.disk_sleep_states.contains(&c));
+                let rss_bytes = status.vmrss.unwrap_or(0) * 1024;
+                let mut swap_fallback = false;
+                if scan.swap_fallback && status.vmswap.is_none() {
+                    if let Some(swap) = smaps_swap(&p) {
+                        status.vmswap = Some(swap);
+                        swap_fallback = true;
+                    }
+                }
+                let mut info = ProcInfo::from(status);
+                info.swap_fallback = swap_fallback;
+                info.extra_fields = extra_fields;
+                info.disk_sleep = disk_sleep;
+                info.over_threshold = scan.big_process_bytes.map(|threshold| rss_bytes > threshold);
+                // other users' fds are unreadable without privileges
+                info.high_fd = scan.high_fd_threshold.and_then(|threshold| Some(p.fd_count().ok()? > threshold));
+                let stat = p.stat().ok();
+                info.nice = stat.as_ref().map(|stat| stat.nice);
+                info.sched_class = stat.as_ref().and_then(|stat| stat.policy).map(sched_class_name);
+                if scan.count_threads {
+                    info.blocked_threads = count_blocked_threads(&p);
+                }
+                if scan.group_by_tty {
+                    info.tty = Some(match &stat {
+                        Some(stat) => {
+                            let (major, minor) = stat.tty_nr();
+                            tty_name(major, minor)
+                        },
+                        None => "unknown".to_string()
+                    });
+                }
+                if scan.group_by_loginuid {
+                    // without audit support there is no loginuid, same as unset
+                    info.loginuid = Some(p.loginuid().unwrap_or(LOGINUID_UNSET));
+                }
+                if scan.group_by_exe {
+                    // kernel threads have no exe, and other users' are unreadable
+                    info.exe = Some(match p.exe() {
+                        Ok(exe) => exe.to_string_lossy().into_owned(),
+                        Err(_) => "unknown".to_string()
+                    });
+                }
+                if scan.group_by_user_command {
+                    info.command = Some(info.status.name.clone());
+                }
+                if scan.numa {
+                    info.numa_kib = numa_kib(&p);
+                }
+                if scan.include_uid_label {
+                    info.uid = Some(info.status.euid);
+                }
+                if scan.detect_containers {
+                    // other users' namespaces are unreadable without privileges
+                    info.pid_ns = Some(match (self_pid_ns, pid_ns_inode(&p)) {
+                        (Some(a), Some(b)) if a == b => "root",
+                        (Some(_), Some(_)) => "child",
+                        _ => "unknown"
+                    });
+                }
+                let age = uptime.zip(stat.as_ref()).map(|(uptime, stat)| uptime - ticks_to_seconds(stat.starttime));
+                match (age, scan.min_process_age) {
+                    (Some(age), Some(min)) if age < min => Err(Excluded{
+                        pid: Some(p.pid),
+                        ppid: Some(info.status.ppid),
+                        reason: format!("only {:.1}s old, under --min-process-age", age)
+                    }),
+                    _ => Ok(info)
+                }
+            },
+            Err(e) => Err(Excluded{pid: Some(p.pid), ppid: None, reason: format!("cannot read status: {}", e)})
+        };
+        if !f(v) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Scan the processes on another thread, giving up at the deadline with
+/// whatever was read so far. Returns whether the deadline was hit.
+fn scan_procs_until(scan: &ScanOptions, deadline: Instant) -> Result<(Vec<ProcInfo>, Vec<Excluded>, bool), ProcError> {
+    let (tx, rx) = mpsc::channel();
+    let thread_scan = scan.clone();
+    // a read stuck on a hung filesystem keeps the thread around, but it
+    // stops as soon as anything else is read after we stop listening
+    std::thread::spawn(move || {
+        let done = walk_procs(&thread_scan, |v| tx.send(Ok(v)).is_ok());
+        if let Err(e) = done {
+            let _ = tx.send(Err(e));
+        }
+    });
+
+    let mut included = Vec::new();
+    let mut excluded = Vec::new();
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(Ok(info))) => included.push(info),
+            Ok(Ok(Err(e))) => excluded.push(e),
+            Ok(Err(e)) => return Err(e),
+            Err(mpsc::RecvTimeoutError::Timeout) => return Ok((included, excluded, true)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok((included, excluded, false))
+        }
+    }
+}
+
+// time between scans when running continuously
+const SCAN_INTERVAL: Duration = Duration::from_secs(15);
+
+// how many times to try listing /proc, and the delay before the first retry
+const SCAN_ATTEMPTS: u32 = 3;
+const SCAN_BACKOFF: Duration = Duration::from_millis(100);
+
+fn get_all_procs(scan: &ScanOptions, failures: &IntCounter, timeouts: &IntCounter) -> Result<(Vec<ProcInfo>, Vec<Excluded>), ProcError> {
+    // Get all processes, retrying with backoff on transient errors like EMFILE
+    let deadline = scan.scrape_timeout.map(|t| Instant::now() + t);
+    let mut backoff = SCAN_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let result = match deadline {
+            Some(deadline) => scan_procs_until(scan, deadline).map(|(procs, excluded, timed_out)| {
+                if timed_out {
+                    warn!("Scan timed out, reporting the {} processes read so far", procs.len());
+                    timeouts.inc();
+                }
+                (procs, excluded)
+            }),
+            None => scan_procs(scan)
+        };
+        match result {
+            Ok(procs) => return Ok(procs),
+            Err(e) => {
+                failures.inc();
+                if attempt >= SCAN_ATTEMPTS {
+                    return Err(e);
+                }
+                warn!("Cannot get processes, retrying in {:?}: {}", backoff, e);
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Sanitize the usernames of the per-user and loginuid groups, merging
+/// any that become the same.
+fn sanitize_usernames(agg: &mut Aggregate) {
+    let mut users: HashMap<UserKey, ProcEntry> = HashMap::new();
+    for (mut key, entry) in agg.users.drain() {
+        key[0] = sanitize_username(&key[0]);
+        users.entry(key).or_default().merge(entry);
+    }
+    agg.users = users;
+    let mut loginusers: HashMap<String, ProcEntry> = HashMap::new();
+    for (name, entry) in agg.loginusers.drain() {
+        loginusers.entry(sanitize_username(&name)).or_default().merge(entry);
+    }
+    agg.loginusers = loginusers;
+    let mut user_commands: HashMap<Vec<String>, ProcEntry> = HashMap::new();
+    for (mut key, entry) in agg.user_commands.drain() {
+        key[0] = sanitize_username(&key[0]);
+        user_commands.entry(key).or_default().merge(entry);
+    }
+    agg.user_commands = user_commands;
+}
+
+/// Apply the filters that need the whole scan, --pid-subtree and
+/// --separate-kernel-memory, to what walk_procs included. Returns the
+/// processes to count, the ones left out and why, and how many of those
+/// were kernel processes.
+fn filter_procs(processes: Vec<ProcInfo>, scan: &ScanOptions) -> (Vec<ProcInfo>, Vec<Excluded>, usize) {
+    let mut excluded = Vec::new();
+    let (processes, outside) = match scan.pid_subtree {
+        Some(root) => pid_subtree(processes, root),
+        None => (processes, Vec::new())
+    };
+    excluded.extend(outside.iter().map(|info| Excluded{
+        pid: Some(info.status.pid),
+        ppid: Some(info.status.ppid),
+        reason: "outside the --pid-subtree".to_string()
+    }));
+    let (kernel, processes): (Vec<_>, Vec<_>) = processes.into_iter()
+        .partition(|info| scan.separate_kernel_memory && info.status.vmrss.is_none());
+    excluded.extend(kernel.iter().map(|info| Excluded{
+        pid: Some(info.status.pid),
+        ppid: Some(info.status.ppid),
+        reason: "no VmRSS, counted in node_kernel_processes".to_string()
+    }));
+    (processes, excluded, kernel.len())
+}
+
+/// Count children over the whole scan, before any filtering, so a fork
+/// bomb's young or foreign children still count for its parent.
+fn scan_children(processes: &[ProcInfo], excluded: &[Excluded]) -> HashMap<i32, i64> {
+    count_children(processes.iter().map(|info| info.status.ppid).chain(excluded.iter().filter_map(|e| e.ppid)))
+}
+
+/// Aggregate the processes as the scan's options ask, with the top-N
+/// folding and username sanitizing.
+fn aggregate_scan(usernames: &UsersCache, processes: &[ProcInfo], children: &HashMap<i32, i64>, scan: &ScanOptions, unit: MemoryUnit) -> Aggregate {
+    let mut agg = aggregate_with_children(usernames, processes, children, unit);
+    agg.ttys = top_n(agg.ttys, scan.tty_top_n, "other".to_string());
+    agg.user_commands = top_n(agg.user_commands, scan.user_command_top_n, vec!["other".to_string(), "other".to_string()]);
+    if scan.sanitize_usernames {
+        sanitize_usernames(&mut agg);
+    }
+    agg
+}
+
+/// Split off `root` and its descendants from the rest. A process whose
+/// parent was excluded from the scan has its own descendants left out
+/// with it.
+fn pid_subtree(processes: Vec<ProcInfo>, root: i32) -> (Vec<ProcInfo>, Vec<ProcInfo>) {
+    if !processes.iter().any(|info| info.status.pid == root) {
+        warn!("--pid-subtree process {} not found, counting nothing", root);
+        return (Vec::new(), processes);
+    }
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for info in &processes {
+        children.entry(info.status.ppid).or_default().push(info.status.pid);
+    }
+    let mut subtree = HashSet::new();
+    let mut todo = vec![root];
+    while let Some(pid) = todo.pop() {
+        // pid 0 is the parent of both init and kthreadd
+        if subtree.insert(pid) && pid != 0 {
+            todo.extend(children.get(&pid).into_iter().flatten());
+        }
+    }
+    processes.into_iter().partition(|info| subtree.contains(&info.status.pid))
+}
+
+// how many users --verbose logs per scan
+const VERBOSE_TOP_USERS: usize = 5;
+
+/// Log a one-line summary of the users with the most RSS.
+fn log_top_users(agg: &Aggregate, labels: &Labels, unit: MemoryUnit) {
+    let mut users: Vec<_> = agg.users.iter().collect();
+    users.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.rss));
+    let to_mib = |x: i64| match unit {
+        MemoryUnit::Bytes => x as f64 / (1 << 20) as f64,
+        MemoryUnit::Kib => x as f64 / 1024.0
+    };
+    let top: Vec<String> = users.iter().take(VERBOSE_TOP_USERS).map(|(key, entry)| {
+        format!("{} {} procs {:.1} MiB rss {:.1} MiB swap", key.join("/"), entry.count, to_mib(entry.rss), to_mib(entry.swap))
+    }).collect();
+    info!("{}: {} users, top by RSS: {}", labels.instance, users.len(), top.join(", "));
+}
+
+/// Scan and publish one target, returning whether the metrics were updated.
+fn procs(usernames: &UsersCache, metrics: &Metrics, labels: &Labels, scan: &ScanOptions, unit: MemoryUnit) -> bool {
+    let ok = scan_and_publish(usernames, metrics, labels, scan, unit);
+    if !scan.abandoned() {
+        metrics.up.with_label_values(&labels.values()).set(ok as i64);
+    }
+    ok
+}
+
+fn scan_and_publish(usernames: &UsersCache, metrics: &Metrics, labels: &Labels, scan: &ScanOptions, unit: MemoryUnit) -> bool {
+    let failures = metrics.scan_failures.with_label_values(&labels.values());
+    let timeouts = metrics.scrape_timeouts.with_label_values(&labels.values());
+    let (processes, excluded) = match get_all_procs(scan, &failures, &timeouts) {
+        Err(_) => {
+            error!("Cannot get processes!");
+            return false;
+        },
+        Ok(x) => x
+    };
+    if scan.abandoned() {
+        // nobody is waiting for a partial scan
+        return false;
+    }
+
+    let swap_fallbacks = processes.iter().filter(|info| info.swap_fallback).count();
+    metrics.swap_fallbacks.with_label_values(&labels.values()).inc_by(swap_fallbacks as u64);
+    let missing_ids = processes.iter().filter(|info| info.status.euid == ID_MISSING || info.status.egid == ID_MISSING).count();
+    metrics.missing_ids.with_label_values(&labels.values()).inc_by(missing_ids as u64);
+
+    if processes.is_empty() && excluded.is_empty() {
+        // most likely a bad procfs mount, so keep the last good series;
+        // processes filtered out by walk_procs still prove it is mounted
+        warn!("no processes found in {}, keeping the previous metrics", scan.root.display());
+        metrics.empty_scrapes.with_label_values(&labels.values()).inc();
+        return false;
+    }
+    let children = scan_children(&processes, &excluded);
+    let (processes, _, kernel) = filter_procs(processes, scan);
+    if scan.separate_kernel_memory {
+        metrics.kernel_processes.with_label_values(&labels.values()).set(kernel as i64);
+    }
+
+    observe_processes(&processes, metrics, labels, unit);
+    let agg = aggregate_scan(usernames, &processes, &children, scan, unit);
+    if scan.verbose {
+        log_top_users(&agg, labels, unit);
+    }
+    publish(agg, metrics, labels);
+    true
+}
+
+/// Shared state for the HTTP endpoint.
+struct ServeState {
+    labels: [String; 3],
+    enable_debug_endpoints: bool,
+    // what /debug/processes scans
+    scans: Vec<ScanOptions>,
+    memory_unit: MemoryUnit,
+    http_requests: IntCounterVec,
+    http_request_duration: Histogram,
+    cors_allow_origin: Option<HeaderValue>,
+    registry: Registry,
+    // the effective configuration, for /config
+    config: serde_json::Value,
+    // only set with --self-metrics-path
+    self_metrics: Option<(String, Registry)>,
+    cache_ttl: Duration,
+    max_response_bytes: usize,
+    timestamps: bool,
+    // the last encoded exposition, and when it was encoded
+    cache: Mutex<Option<(Instant, Bytes)>>
+}
+
+impl ServeState {
+    fn new(args: &Args, names: &LabelNames, labels: &Labels, targets: &[Target], registries: &Registries, config: serde_json::Value) -> prometheus::Result<ServeState> {
+        let registry = registries.self_registry();
+        let http_requests = register_int_counter_vec_with_registry!(opts!(
+            "proc_mem_to_prom_http_requests_total",
+            "The number of HTTP requests served, by status code."),
+            &[names[0], names[1], names[2], "code"],
+            registry
+        )?;
+        let http_request_duration = register_histogram_vec_with_registry!(histogram_opts!(
+            "proc_mem_to_prom_http_request_duration_seconds",
+            "The time taken to serve HTTP requests."),
+            names,
+            registry
+        )?;
+        Ok(ServeState {
+            labels: labels.values().map(|x| x.to_string()),
+            enable_debug_endpoints: args.enable_debug_endpoints,
+            scans: targets.iter().map(|t| t.scan.clone()).collect(),
+            memory_unit: args.memory_unit,
+            http_requests,
+            http_request_duration: http_request_duration.with_label_values(&labels.values()),
+            // checked by parse_origin
+            cors_allow_origin: args.cors_allow_origin.as_deref().map(|origin| HeaderValue::from_str(origin).unwrap()),
+            registry: registries.main.clone(),
+            config,
+            self_metrics: args.self_metrics_path.clone().zip(registries.separate_self.clone()),
+            cache_ttl: Duration::from_secs_f64(args.cache_ttl),
+            max_response_bytes: args.max_response_bytes,
+            timestamps: registries.timestamps,
+            cache: Mutex::new(None)
+        })
+    }
+
+    fn record(&self, code: u16, start: Instant) {
+        let [job, hostgroup, instance] = &self.labels;
+        self.http_requests.with_label_values(&[job, hostgroup, instance, &code.to_string()]).inc();
+        self.http_request_duration.observe(start.elapsed().as_secs_f64());
+    }
+
+    /// Get the encoded exposition, reusing the cached copy if still fresh.
+    fn exposition(&self) -> Bytes {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((encoded_at, buffer)) = cache.as_ref() {
+            if encoded_at.elapsed() < self.cache_ttl {
+                return buffer.clone();
+            }
+        }
+
+        let buffer = Bytes::from(encode_metrics(&self.registry.gather(), self.timestamps));
+        *cache = Some((Instant::now(), buffer.clone()));
+        buffer
+    }
+}
 
-    #[arg(long)]
-    group: Option<String>,
-    
-    #[arg(long)]
-    instance: Option<String>
+// set once run_forever has scanned every target, for /readyz
+static FIRST_SCRAPE_DONE: AtomicBool = AtomicBool::new(false);
+
+fn probe_response(ok: bool) -> Response<Body> {
+    let (status, body) = if ok { (200, "ok\n") } else { (503, "not ready\n") };
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
 }
 
-fn get_all_procs() -> Result<Vec<Status>, ProcError> {
-    // Get all processes
-    Ok(all_processes()?
-    .filter_map(|v| {
-        v.and_then(|p| {
-            Ok(p.status()?)
-        })
-        .ok()
-    })
-    .collect())
+async fn serve_req(req: Request<Body>, state: Arc<ServeState>) -> Result<Response<Body>, hyper::Error> {
+    let start = Instant::now();
+    // the peer, or the original client with --proxy-protocol
+    let client = req.extensions().get::<SocketAddr>().copied();
+    let request_line = format!("{} {}", req.method(), req.uri().path());
+    let mut response = if req.uri().path() == "/livez" {
+        probe_response(true)
+    } else if req.uri().path() == "/readyz" {
+        probe_response(FIRST_SCRAPE_DONE.load(Ordering::Relaxed))
+    } else if state.enable_debug_endpoints && req.uri().path() == "/debug/processes" {
+        let scans = state.scans.clone();
+        let unit = state.memory_unit;
+        json_response(tokio::task::spawn_blocking(move || debug_processes(&scans, unit)).await.unwrap())
+    } else if state.enable_debug_endpoints && req.uri().path() == "/config" {
+        json_response(Ok(state.config.clone()))
+    } else if state.enable_debug_endpoints && req.uri().path() == "/metadata" {
+        let mut families = state.registry.gather();
+        if let Some((_, registry)) = &state.self_metrics {
+            families.extend(registry.gather());
+        }
+        json_response(Ok(metadata(&families)))
+    } else {
+        handle_req(req, &state)
+    };
+    if let Some(origin) = &state.cors_allow_origin {
+        response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    }
+    state.record(response.status().as_u16(), start);
+    match client {
+        Some(client) => debug!("{} from {}: {}", request_line, client, response.status()),
+        None => debug!("{}: {}", request_line, response.status())
+    }
+    Ok(response)
 }
 
-struct ProcEntry {
-    count: i64,
-    rss: i64,
-    swap: i64
+/// Listen on `addr`, with the socket options hyper can't set.
+fn bind_listener(addr: SocketAddr, reuse_addr: bool, reuse_port: bool) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(reuse_addr)?;
+    socket.set_reuse_port(reuse_port)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
 }
 
-fn procs(usernames: &UsersCache, hostgroup: &str, instance: &str) {
-    let processes = match get_all_procs() {
-        Err(_) => {
-            println!("Cannot get processes!");
+/// Serve plain HTTP until shutdown is signalled.
+async fn serve(listener: std::net::TcpListener, state: Arc<ServeState>, mut shutdown: watch::Receiver<bool>) {
+    // from the bound socket, so --port 0 logs the port actually chosen
+    match listener.local_addr() {
+        Ok(addr) => info!("Listening on http://{}", addr),
+        Err(e) => warn!("cannot get the listening address: {}", e)
+    }
+    let builder = match Server::from_tcp(listener) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("server error: {}", e);
             return;
-        },
-        Ok(procs) => procs
+        }
     };
-    let mut user_procs = HashMap::new();
+    let serve_future = builder.serve(make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(peer);
+                serve_req(req, state.clone())
+            }))
+        }
+    }));
 
-    for process in &processes {
-        let user = usernames.get_user_by_uid(process.euid);
-        let username = match &user {
-            Some(x) => x.name().to_str().unwrap(),
-            None => "unknown"
+    // in-flight requests finish before this returns
+    let serve_future = serve_future.with_graceful_shutdown(async move {
+        let _ = shutdown.changed().await;
+    });
+    if let Err(err) = serve_future.await {
+        error!("server error: {}", err);
+    }
+}
+
+// how long a new connection has to send its PROXY header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve HTTP on connections that start with a PROXY protocol header,
+/// which hyper's server can't handle. The client address from the header
+/// is added to each request's extensions, for the request log.
+///
+/// Stops accepting on shutdown, then waits for open connections to finish
+/// their requests.
+async fn serve_proxied(listener: std::net::TcpListener, state: Arc<ServeState>, mut shutdown: watch::Receiver<bool>) {
+    let listener = match TcpListener::from_std(listener) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("server error: {}", e);
+            return;
+        }
+    };
+    if let Ok(addr) = listener.local_addr() {
+        info!("Listening on http://{} with PROXY protocol", addr);
+    }
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            x = listener.accept() => x,
+            // reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next() => continue,
+            _ = shutdown.changed() => break
         };
-        let entry = user_procs.entry(username.to_string()).or_insert(ProcEntry{count: 0, rss: 0, swap: 0});
-        entry.count += 1;
-        entry.rss += match process.vmrss {
-            Some(x) => x as i64,
-            None => 0
-        } * 1000;
-        entry.swap += match process.vmswap {
-            Some(x) => x as i64,
-            None => 0
-        } * 1000;
-    }
-
-    let prev_metrics = USER_PROCESSES_GAUGE.collect();
-    let mut prev_usernames = HashSet::with_capacity(prev_metrics.len());
-    for m in &prev_metrics {
-        for mm in m.get_metric() {
-            match mm.get_label().last() {
-                Some(x) => {
-                    prev_usernames.insert(x.get_value());
+        let (mut stream, peer) = match accepted {
+            Ok(x) => x,
+            Err(e) => {
+                error!("cannot accept connection: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let mut shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let client = match timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+                Ok(Ok(addr)) => addr.unwrap_or(peer),
+                Ok(Err(e)) => {
+                    warn!("rejecting connection from {}: {}", peer, e);
+                    return;
                 },
-                None => { }
+                Err(_) => {
+                    warn!("rejecting connection from {}: no PROXY header in {:?}", peer, PROXY_HEADER_TIMEOUT);
+                    return;
+                }
+            };
+            let service = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(client);
+                serve_req(req, state.clone())
+            });
+            let conn = Http::new().serve_connection(stream, service);
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                x = conn.as_mut() => x,
+                _ = shutdown.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                warn!("connection error from {}: {}", client, e);
             }
+        });
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() { }
+}
+
+// the media type of the delimited protobuf exposition format
+const PROTOBUF_TYPE: &str = "application/vnd.google.protobuf";
+
+/// Whether the Accept header weighs the protobuf format above text.
+/// Without a header, or on a tie, text is used.
+fn wants_protobuf(accept: Option<&HeaderValue>) -> bool {
+    let accept = match accept.and_then(|x| x.to_str().ok()) {
+        Some(x) => x,
+        None => return false
+    };
+    let (mut protobuf, mut text) = (0.0f64, 0.0f64);
+    for range in accept.split(',') {
+        let mut parts = range.split(';').map(str::trim);
+        let media = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|q| q.parse::<f64>().ok())
+            .unwrap_or(1.0);
+        match media.as_str() {
+            PROTOBUF_TYPE => protobuf = protobuf.max(q),
+            "text/plain" | "text/*" | "*/*" => text = text.max(q),
+            _ => { }
         }
     }
+    protobuf > 0.0 && protobuf > text
+}
 
-    for (user, entry) in user_procs.into_iter() {
-        let username = user.as_str();
-        USER_PROCESSES_GAUGE.with_label_values(
-            &["proc-mem-to-prom", hostgroup, instance, username]
-        ).set(entry.count);
-        USER_MEMORY_GAUGE.with_label_values(
-            &["proc-mem-to-prom", hostgroup, instance, username]
-        ).set(entry.rss);
-        USER_SWAP_GAUGE.with_label_values(
-            &["proc-mem-to-prom", hostgroup, instance, username]
-        ).set(entry.swap);
-        prev_usernames.remove(username);
+fn handle_req(req: Request<Body>, state: &ServeState) -> Response<Body> {
+    if state.cors_allow_origin.is_some() && req.method() == Method::OPTIONS {
+        let response = Response::builder()
+            .status(204)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, HEAD, OPTIONS")
+            .body(Body::empty())
+            .unwrap();
+        return response;
     }
 
-    for username in &prev_usernames {
-        match USER_PROCESSES_GAUGE.remove_label_values(
-            &["proc-mem-to-prom", hostgroup, instance, username]
-        ) {
-            _ => { }
+    let protobuf = wants_protobuf(req.headers().get(ACCEPT));
+    let registry = match &state.self_metrics {
+        Some((path, registry)) if req.uri().path() == path => Some(registry),
+        _ => None
+    };
+    // only the text format is cached, since it is what most scrapers use
+    let buffer = match (registry, protobuf) {
+        (Some(registry), false) => Bytes::from(encode_metrics(&registry.gather(), state.timestamps)),
+        (None, false) => state.exposition(),
+        (registry, true) => {
+            let families = registry.unwrap_or(&state.registry).gather();
+            Bytes::from(encode_with(&ProtobufEncoder::new(), &families, state.timestamps))
         }
-        match USER_MEMORY_GAUGE.remove_label_values(
-            &["proc-mem-to-prom", hostgroup, instance, username]
-        ) {
-            _ => { }
+    };
+    if buffer.len() > state.max_response_bytes {
+        warn!("not sending a {} byte response, over --max-response-bytes", buffer.len());
+        return Response::builder()
+            .status(500)
+            .body(Body::from("response too large\n"))
+            .unwrap();
+    }
+    let content_type = if protobuf {
+        ProtobufEncoder::new().format_type().to_string()
+    } else {
+        TextEncoder::new().format_type().to_string()
+    };
+
+    let builder = Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, buffer.len());
+    // HEAD gets the same headers, but no body
+    let body = match *req.method() {
+        Method::HEAD => Body::empty(),
+        _ => Body::from(buffer)
+    };
+    builder.body(body).unwrap()
+}
+
+fn json_response(result: Result<serde_json::Value, String>) -> Response<Body> {
+    let (status, value) = match result {
+        Ok(value) => (200, value),
+        Err(e) => (500, json!({"error": e}))
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+// how many processes /debug/processes lists
+const DEBUG_PROCESSES_LIMIT: usize = 50;
+
+/// List the processes with the most RSS, across every target.
+fn debug_processes(scans: &[ScanOptions], unit: MemoryUnit) -> Result<serde_json::Value, String> {
+    let usernames = UsersCache::new();
+    let mut processes = Vec::new();
+    for scan in scans {
+        let (found, _) = scan_procs(scan).map_err(|e| e.to_string())?;
+        processes.extend(found.into_iter().map(|p| (&scan.root, p)));
+    }
+    processes.sort_by_key(|(_, p)| std::cmp::Reverse(p.status.vmrss));
+    processes.truncate(DEBUG_PROCESSES_LIMIT);
+    Ok(processes.iter().map(|(root, p)| {
+        let user = usernames.get_user_by_uid(p.status.euid);
+        json!({
+            "root": root,
+            "pid": p.status.pid,
+            "comm": p.status.name,
+            "user": username_for(&user),
+            "rss": unit.convert(p.status.vmrss),
+            "swap": unit.convert(p.status.vmswap)
+        })
+    }).collect())
+}
+
+/// The type, help, and unit of each metric, in the shape of Prometheus'
+/// /api/v1/metadata. The unit is guessed from the name's suffix.
+fn metadata(families: &[MetricFamily]) -> serde_json::Value {
+    let data: serde_json::Map<_, _> = families.iter().map(|family| {
+        let kind = match family.get_field_type() {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::SUMMARY => "summary",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::UNTYPED => "unknown"
+        };
+        let name = family.get_name().trim_end_matches("_total");
+        let unit = ["bytes", "kib", "seconds"].into_iter()
+            .find(|unit| name.ends_with(&format!("_{}", unit)))
+            .unwrap_or("");
+        (family.get_name().to_string(), json!([{"type": kind, "help": family.get_help(), "unit": unit}]))
+    }).collect();
+    json!({"status": "success", "data": data})
+}
+
+/// Encode metric families in the text exposition format, optionally
+/// with the current time on every sample.
+fn encode_metrics(families: &[MetricFamily], timestamps: bool) -> Vec<u8> {
+    encode_with(&TextEncoder::new(), families, timestamps)
+}
+
+fn encode_with<E: Encoder>(encoder: &E, families: &[MetricFamily], timestamps: bool) -> Vec<u8> {
+    let mut buffer = Vec::<u8>::new();
+    if timestamps {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0);
+        let mut families = families.to_vec();
+        for metric in families.iter_mut().flat_map(|family| family.mut_metric().iter_mut()) {
+            metric.set_timestamp_ms(now);
         }
-        match USER_SWAP_GAUGE.remove_label_values(
-            &["proc-mem-to-prom", hostgroup, instance, username]
-        ) {
-            _ => { }
+        encoder.encode(&families, &mut buffer).unwrap();
+    } else {
+        encoder.encode(families, &mut buffer).unwrap();
+    }
+    buffer
+}
+
+/// Where the metrics are registered.
+struct Registries {
+    main: Registry,
+    // only with --self-metrics-path, otherwise self metrics are in main
+    separate_self: Option<Registry>,
+    // whether encoded samples carry timestamps, with --emit-timestamps
+    timestamps: bool,
+    // the gauge values last emitted, only with --emit-changed-only
+    emitted: Option<Mutex<HashMap<String, f64>>>
+}
+
+impl Registries {
+    /// Use the default registry, unless there are constant labels to
+    /// apply, which can only be set on a new registry, or `clean` asks for
+    /// a registry without whatever the prometheus crate registers itself.
+    fn new(const_labels: HashMap<String, String>, separate_self: bool, timestamps: bool, clean: bool) -> prometheus::Result<Registries> {
+        let main = if const_labels.is_empty() && !clean {
+            prometheus::default_registry().clone()
+        } else {
+            Registry::new_custom(None, Some(const_labels.clone()))?
+        };
+        let separate_self = if separate_self {
+            Some(Registry::new_custom(None, Some(const_labels))?)
+        } else {
+            None
+        };
+        Ok(Registries{main, separate_self, timestamps, emitted: None})
+    }
+
+    fn self_registry(&self) -> &Registry {
+        self.separate_self.as_ref().unwrap_or(&self.main)
+    }
+
+    /// Gather the main registry, plus the self registry if separate,
+    /// for the push and textfile outputs.
+    fn gather_all(&self) -> Vec<MetricFamily> {
+        let mut families = self.main.gather();
+        if let Some(registry) = &self.separate_self {
+            families.extend(registry.gather());
+            families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        }
+        match &self.emitted {
+            Some(emitted) => drop_unchanged(families, &mut emitted.lock().unwrap()),
+            None => families
         }
     }
 }
 
-async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    let mut buffer = Vec::<u8>::new();
-    let encoder = TextEncoder::new();
-    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+/// Leave out the gauge samples with the same value as when last emitted,
+/// and the families left empty. Other metric types are always kept.
+fn drop_unchanged(mut families: Vec<MetricFamily>, emitted: &mut HashMap<String, f64>) -> Vec<MetricFamily> {
+    let mut next = HashMap::new();
+    for family in families.iter_mut().filter(|f| f.get_field_type() == MetricType::GAUGE) {
+        let name = family.get_name().to_string();
+        let metrics = family.take_metric().into_iter().filter(|m| {
+            let labels: Vec<_> = m.get_label().iter().map(|l| format!("{}={:?}", l.get_name(), l.get_value())).collect();
+            let key = format!("{}{{{}}}", name, labels.join(","));
+            let value = m.get_gauge().get_value();
+            let changed = emitted.get(&key) != Some(&value);
+            next.insert(key, value);
+            changed
+        }).collect();
+        family.set_metric(metrics);
+    }
+    // forget series that are gone, so they are sent if they come back
+    *emitted = next;
+    families.retain(|f| !f.get_metric().is_empty());
+    families
+}
 
-    let response = Response::builder()
-        .status(200)
-        .header(CONTENT_TYPE, encoder.format_type())
-        .body(Body::from(buffer))
-        .unwrap();
-    Ok(response)
+/// Where the metrics go after each scrape.
+enum Output {
+    /// Served by the HTTP server task, so nothing to do per scrape.
+    Http,
+    /// Written to a file for the node_exporter textfile collector.
+    Textfile(PathBuf),
+    /// Pushed with the remote-write protocol.
+    RemoteWrite(RemoteWrite),
+    /// Sent as Graphite plaintext lines.
+    Graphite(Graphite)
 }
 
+impl Output {
+    fn from_args(args: &Args, names: LabelNames) -> Result<Output, String> {
+        if let Some(url) = &args.remote_write {
+            return Ok(Output::RemoteWrite(RemoteWrite::new(url, args.remote_write_auth.clone())?));
+        }
+        if let Some(addr) = &args.graphite {
+            return Ok(Output::Graphite(Graphite::new(addr, names)));
+        }
+        Ok(match &args.textfile {
+            Some(path) => Output::Textfile(path.clone()),
+            None => Output::Http
+        })
+    }
 
-fn oneshot(group: &str, instance: &str) {
-    let usernames = UsersCache::new();
-    procs(&usernames, group, instance);
+    /// Send everything, including a separate self registry, since only
+    /// HTTP has somewhere else to put it.
+    async fn emit(&self, registries: &Registries) {
+        match self {
+            Output::Http => { },
+            Output::Textfile(path) => {
+                if let Err(e) = write_textfile(path, &encode_metrics(&registries.gather_all(), registries.timestamps)) {
+                    error!("Cannot write {}: {}", path.display(), e);
+                }
+            },
+            Output::RemoteWrite(rw) => rw.push(&registries.gather_all()).await,
+            Output::Graphite(g) => g.send(&registries.gather_all()).await
+        }
+    }
+}
+
+/// Write via a temporary file and rename it into place, so readers
+/// never see a partial file.
+fn write_textfile(path: &Path, buffer: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, buffer)?;
+    fs::rename(&tmp, path)
+}
+
+/// Scan once and print the metrics, with headers unless `quiet`.
+fn oneshot(usernames: &Usernames, metrics: &Metrics, labels: &Labels, targets: &[Target], unit: MemoryUnit, registries: &Registries, quiet: bool) {
+    for target in targets {
+        procs(&usernames.cache.lock().unwrap(), metrics, &target.labels(), &target.scan, unit);
+    }
+    self_metrics(metrics, labels, usernames, targets, unit);
     
     // Print metrics for the default registry.
-    let mut buffer = Vec::<u8>::new();
-    let encoder = TextEncoder::new();
-    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    let buffer = encode_metrics(&registries.main.gather(), registries.timestamps);
+    if quiet {
+        // just the exposition, for piping into other tools
+        print!("{}", String::from_utf8(buffer).unwrap());
+        if let Some(registry) = &registries.separate_self {
+            print!("{}", String::from_utf8(encode_metrics(&registry.gather(), registries.timestamps)).unwrap());
+        }
+        return;
+    }
     println!("## Default registry");
     println!("{}", String::from_utf8(buffer.clone()).unwrap());
+    if let Some(registry) = &registries.separate_self {
+        println!("## Self registry");
+        println!("{}", String::from_utf8(encode_metrics(&registry.gather(), registries.timestamps)).unwrap());
+    }
+}
+
+/// Check that histogram buckets are finite and strictly increasing, as
+/// prometheus requires of them on the first observation.
+fn check_buckets(buckets: &[f64]) -> Result<(), String> {
+    if let Some(bucket) = buckets.iter().find(|bucket| !bucket.is_finite()) {
+        return Err(format!("bucket {} is not finite", bucket));
+    }
+    match buckets.windows(2).find(|pair| pair[0] >= pair[1]) {
+        Some(pair) => Err(format!("buckets are not increasing: {} then {}", pair[0], pair[1])),
+        None => Ok(())
+    }
+}
+
+fn rss_buckets(args: &Args) -> Vec<f64> {
+    if args.rss_histogram_buckets.is_empty() {
+        default_rss_buckets(args.memory_unit)
+    } else {
+        args.rss_histogram_buckets.clone()
+    }
+}
+
+/// Print the metrics registered with every option on, so none are missing.
+fn list_metrics(args: &Args, names: &LabelNames, labels: &Labels, scan: &ScanOptions) {
+    let registries = Registries{main: Registry::new(), separate_self: None, timestamps: false, emitted: None};
+    let options = MetricsOptions {
+        label_names: *names,
+        unit: args.memory_unit,
+        user_labels: scan.user_labels(),
+        ema_alpha: Some(1.0),
+        omit_zero_swap: false,
+        emit_deltas: true,
+        extra_status_fields: args.extra_status_field.clone(),
+        count_churn: true,
+        rss_window: Some(Duration::from_secs(300)),
+        rss_histogram: Some((args.rss_histogram_scope.unwrap_or(RssHistogramScope::User), rss_buckets(args)))
+    };
+    let registered = Metrics::new(&registries.main, registries.self_registry(), &options)
+        .and_then(|metrics| Ok((metrics, ServeState::new(args, names, labels, &[], &registries, serde_json::Value::Null)?)));
+    let (metrics, state) = match registered {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Cannot register metrics: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut list = metrics.list();
+    list.push((&state.http_requests, Some("HTTP output")));
+    list.push((&state.http_request_duration, Some("HTTP output")));
+    for (collector, flag) in list {
+        for desc in collector.desc() {
+            let mut labels: Vec<&str> = desc.const_label_pairs.iter().map(|l| l.get_name()).collect();
+            labels.extend(desc.variable_labels.iter().map(|l| l.as_str()));
+            println!("{}{{{}}}", desc.fq_name, labels.join(","));
+            println!("    {}", desc.help);
+            if let Some(flag) = flag {
+                println!("    only with {}", flag);
+            }
+        }
+    }
 }
 
-async fn run_forever(group: &str, instance: &str) {
+fn dry_run(targets: &[Target], unit: MemoryUnit) {
+    println!("clock ticks per second: {}", proc_mem_to_prom::clock::ticks_per_second());
+    for target in targets {
+        dry_run_target(&target.scan, unit);
+    }
+}
+
+fn dry_run_target(scan: &ScanOptions, unit: MemoryUnit) {
     let usernames = UsersCache::new();
+    println!();
+    let (processes, mut excluded) = match scan_procs(scan) {
+        Err(e) => {
+            println!("Cannot get processes in {}: {}", scan.root.display(), e);
+            return;
+        },
+        Ok(x) => x
+    };
+    let children = scan_children(&processes, &excluded);
+    let (processes, filtered, _) = filter_procs(processes, scan);
+    excluded.extend(filtered);
+
+    println!("## Processes in {}", scan.root.display());
+    for process in &processes {
+        let user = usernames.get_user_by_uid(process.status.euid);
+        println!("pid {} included: user {}", process.status.pid, username_for(&user));
+    }
+    for e in &excluded {
+        match e.pid {
+            Some(pid) => println!("pid {} excluded: {}", pid, e.reason),
+            None => println!("unknown pid excluded: {}", e.reason)
+        }
+    }
+
+    let agg = aggregate_scan(&usernames, &processes, &children, scan, unit);
+    let mut users: Vec<_> = agg.users.iter().collect();
+    users.sort_by(|a, b| a.0.cmp(b.0));
+    println!();
+    println!("## Aggregation by user in {}", scan.root.display());
+    for (key, entry) in users {
+        println!("{}: processes={} rss={} swap={} max_threads={} blocked={}", key.join(" "), entry.count, entry.rss, entry.swap, entry.max_threads, entry.blocked);
+    }
+}
+
+/// The user name lookups, and when they started being cached.
+struct Usernames {
+    // shared with the blocking scan threads
+    cache: Arc<Mutex<UsersCache>>,
+    built: Instant
+}
+
+impl Usernames {
+    fn new() -> Usernames {
+        Usernames{cache: Arc::new(Mutex::new(UsersCache::new())), built: Instant::now()}
+    }
+}
+
+/// Update the exporter's own fd and thread counts, to catch leaks, and
+/// the age of the user name cache. With --exclude-self, its RSS too, since
+/// it is no longer counted for a user.
+fn self_metrics(metrics: &Metrics, labels: &Labels, usernames: &Usernames, targets: &[Target], unit: MemoryUnit) {
+    metrics.users_cache_age.with_label_values(&labels.values()).set(usernames.built.elapsed().as_secs_f64());
+    if let Ok(me) = Process::myself() {
+        if targets.iter().any(|t| t.scan.exclude_self) {
+            if let Ok(status) = me.status() {
+                metrics.self_memory.with_label_values(&labels.values()).set(unit.convert(status.vmrss));
+            }
+        }
+        if let Ok(fds) = me.fd_count() {
+            metrics.self_open_fds.with_label_values(&labels.values()).set(fds as i64);
+        }
+        if let Ok(stat) = me.stat() {
+            metrics.self_threads.with_label_values(&labels.values()).set(stat.num_threads);
+            // the counter can only go up, so add what was used since last time
+            let cpu = metrics.self_cpu.with_label_values(&labels.values());
+            let seconds = ticks_to_seconds(stat.utime + stat.stime);
+            if seconds > cpu.get() {
+                cpu.inc_by(seconds - cpu.get());
+            }
+        }
+    }
+}
+
+async fn run_forever(usernames: &Usernames, metrics: &Arc<Metrics>, labels: &Labels<'_>, targets: &[Target], unit: MemoryUnit, output: &Output, registries: &Registries) {
     loop {
         let start = Instant::now();
-        procs(&usernames, group, instance);
-        sleep(Duration::from_secs(15) - start.elapsed()).await;
+        scrape(usernames, metrics, labels, targets, unit, output, registries).await;
+        sleep(SCAN_INTERVAL.saturating_sub(start.elapsed())).await;
+    }
+}
+
+/// Scan every target and emit the result.
+async fn scrape(usernames: &Usernames, metrics: &Arc<Metrics>, labels: &Labels<'_>, targets: &[Target], unit: MemoryUnit, output: &Output, registries: &Registries) {
+    let mut ok = true;
+    for target in targets {
+        let labels = target.labels();
+        let in_progress = metrics.scrape_in_progress.with_label_values(&labels.values());
+        metrics.scrapes.with_label_values(&labels.values()).inc();
+        in_progress.set(1);
+        // reading /proc blocks, so keep it off the runtime's threads, which
+        // have signals and HTTP requests to handle meanwhile
+        let (cache, metrics, mut target) = (usernames.cache.clone(), metrics.clone(), target.clone());
+        // dropped with this future, which stops the walk
+        let waiting = Arc::new(());
+        target.scan.scrape = Some(Arc::downgrade(&waiting));
+        let scan = tokio::task::spawn_blocking(move || {
+            procs(&cache.lock().unwrap(), &metrics, &target.labels(), &target.scan, unit)
+        });
+        ok &= match scan.await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("scan task failed: {}", e);
+                false
+            }
+        };
+        in_progress.set(0);
+    }
+    if ok {
+        FIRST_SCRAPE_DONE.store(true, Ordering::Relaxed);
+    }
+    self_metrics(metrics, labels, usernames, targets, unit);
+    output.emit(registries).await;
+}
+
+/// Install the SIGTERM and SIGINT handlers, returning a future that waits
+/// for either and says whether it was SIGTERM. Installing them before
+/// anything else runs keeps an early signal from killing the process.
+fn shutdown_signal() -> impl std::future::Future<Output = bool> {
+    let listen = |kind: SignalKind, name: &str| match signal(kind) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            error!("cannot handle {}: {}", name, e);
+            None
+        }
+    };
+    let mut term = listen(SignalKind::terminate(), "SIGTERM");
+    let mut int = listen(SignalKind::interrupt(), "SIGINT");
+    async move {
+        tokio::select! {
+            _ = recv(&mut term) => true,
+            _ = recv(&mut int) => false
+        }
+    }
+}
+
+async fn recv(signal: &mut Option<Signal>) {
+    match signal {
+        Some(signal) => {
+            signal.recv().await;
+        },
+        None => std::future::pending().await
     }
 }
 
@@ -185,19 +1844,37 @@ async fn run_forever(group: &str, instance: &str) {
 async fn main() {
     // get config
     let args = Args::parse();
+    let level = if args.quiet {
+        LevelFilter::Warn
+    } else if args.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    if let Err(e) = logging::init(args.log_format, level) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    debug!("clock ticks per second: {}", proc_mem_to_prom::clock::ticks_per_second());
 
     let env_port = env::var("PORT");
     let port = match args.port {
         Some(x) => x,
         None => match env_port {
-            Ok(x) => match x.parse::<u16>() {
-                Ok(x) => x,
-                Err(_) => 0,
-            },
+            Ok(x) => x.parse::<u16>().unwrap_or_default(),
             Err(_) => 0
         }
     };
     
+    let env_job = env::var("JOB");
+    let job = match &args.job {
+        Some(x) => x.as_str(),
+        None => match &env_job {
+            Ok(x) => x.as_str(),
+            Err(_) => "proc-mem-to-prom"
+        }
+    };
+
     let env_group = env::var("GROUP");
     let group = match &args.group {
         Some(x) => x.as_str(),
@@ -208,32 +1885,357 @@ async fn main() {
     };
 
     let env_instance = env::var("INSTANCE");
+    let host_instance = if args.instance_fqdn { hostname().map(|host| fqdn(&host).unwrap_or(host)) } else { None };
     let instance = match &args.instance {
         Some(x) => x.as_str(),
-        None => match &env_instance {
-            Ok(x) => x.as_str(),
-            Err(_) => "test"
+        None => match (&env_instance, &host_instance) {
+            (Ok(x), _) => x.as_str(),
+            (Err(_), Some(x)) => x.as_str(),
+            (Err(_), None) => "test"
+        }
+    };
+
+    let checked = [("job", job), ("group", group), ("instance", instance)].map(|(name, value)| {
+        match check_label(name, value, args.strict_labels) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    });
+    let labels = Labels {
+        job: &checked[0],
+        hostgroup: &checked[1],
+        instance: &checked[2]
+    };
+    let scan = ScanOptions::from_args(&args);
+    if scan.uid_range.is_empty() {
+        eprintln!("--uid-min is above --uid-max, so no process would be counted");
+        std::process::exit(1);
+    }
+    if let Err(e) = check_buckets(&args.rss_histogram_buckets) {
+        eprintln!("invalid --rss-histogram-buckets: {}", e);
+        std::process::exit(1);
+    }
+    for (field, _) in &args.extra_status_field {
+        if let Err(e) = probe_status_field(field) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let label_names = match label_names(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let const_labels = match const_labels(&args, &label_names) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     };
 
-    if args.oneshot {
-        oneshot(&group, &instance);
+    let mut targets = Vec::new();
+    if args.target.is_empty() {
+        targets.push(Target{labels: checked.clone(), scan: scan.clone()});
+    }
+    for t in &args.target {
+        let check = |name, value: &Option<String>, default: &String| match value {
+            Some(v) => check_label(name, v, args.strict_labels).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }),
+            None => default.clone()
+        };
+        targets.push(Target {
+            labels: [
+                checked[0].clone(),
+                check("group", &t.group, &checked[1]),
+                check("instance", &t.instance, &checked[2])
+            ],
+            scan: ScanOptions{root: t.root.clone(), ..scan.clone()}
+        });
+    }
+
+    // each target's stale series cleanup would remove the other's
+    let mut seen = HashSet::new();
+    for target in &targets {
+        if !seen.insert(&target.labels) {
+            eprintln!("--target root={} has the same group and instance as another target", target.scan.root.display());
+            std::process::exit(1);
+        }
+    }
+
+    if args.list_metrics {
+        list_metrics(&args, &label_names, &labels, &scan);
         return;
-    } else {
-        // set up prometheus http reporter
-        tokio::spawn(async move {
-            let addr = ([0, 0, 0, 0], port).into();
+    }
+
+    if args.dry_run {
+        dry_run(&targets, args.memory_unit);
+        return;
+    }
+
+    let output = match Output::from_args(&args, label_names) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let usernames = Usernames::new();
+    let config = json!({
+        "args": &args,
+        "port": port,
+        "const_labels": &const_labels,
+        "targets": targets.iter().map(|t| json!({
+            "root": t.scan.root,
+            "job": t.labels[0],
+            "hostgroup": t.labels[1],
+            "instance": t.labels[2]
+        })).collect::<Vec<_>>()
+    });
+    let mut registries = match Registries::new(const_labels, args.self_metrics_path.is_some(), args.emit_timestamps, args.no_default_collectors) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.emit_changed_only {
+        if let Output::Http | Output::Textfile(_) = output {
+            // Prometheus needs every sample on every scrape, and node_exporter
+            // serves the whole textfile on each one
+            eprintln!("--emit-changed-only needs --remote-write or --graphite");
+            std::process::exit(1);
+        }
+        registries.emitted = Some(Mutex::new(HashMap::new()));
+    }
+    let options = MetricsOptions {
+        label_names,
+        unit: args.memory_unit,
+        user_labels: scan.user_labels(),
+        ema_alpha: args.ema_alpha,
+        omit_zero_swap: args.omit_zero_swap,
+        emit_deltas: args.emit_deltas,
+        extra_status_fields: args.extra_status_field.clone(),
+        count_churn: args.count_churn,
+        rss_window: args.rss_window_seconds.map(Duration::from_secs_f64),
+        rss_histogram: args.rss_histogram_scope.map(|scope| (scope, rss_buckets(&args)))
+    };
+    let metrics = match Metrics::new(&registries.main, registries.self_registry(), &options) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            eprintln!("Cannot register metrics: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for target in &targets {
+        metrics.scrape_interval.with_label_values(&target.labels().values()).set(SCAN_INTERVAL.as_secs_f64());
+        // up until a scan fails
+        metrics.up.with_label_values(&target.labels().values()).set(1);
+    }
 
-            let serve_future = Server::bind(&addr).serve(make_service_fn(|_| async {
-                Ok::<_, hyper::Error>(service_fn(serve_req))
-            }));
-            println!("Listening on http://{}", serve_future.local_addr());
+    if args.oneshot || args.serve_after_oneshot {
+        oneshot(&usernames, &metrics, &labels, &targets, args.memory_unit, &registries, args.quiet);
+        if !args.serve_after_oneshot {
+            return;
+        }
+    }
 
-            if let Err(err) = serve_future.await {
-                eprintln!("server error: {}", err);
+    let shutdown = shutdown_signal();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut servers = Vec::new();
+    if let Output::Http = output {
+        // set up prometheus http reporter
+        let state = match ServeState::new(&args, &label_names, &labels, &targets, &registries, config) {
+            Ok(x) => Arc::new(x),
+            Err(e) => {
+                eprintln!("Cannot register metrics: {}", e);
+                std::process::exit(1);
             }
-        });
-        // run prometheus
-        run_forever(&group, &instance).await;
+        };
+        let addrs = if args.listen.is_empty() {
+            vec![SocketAddr::from(([0, 0, 0, 0], port))]
+        } else {
+            args.listen.clone()
+        };
+        // bind everything before serving anything, so a bad address fails the start
+        let listeners: Vec<_> = addrs.iter().map(|&addr| match bind_listener(addr, args.reuse_addr, args.reuse_port) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Cannot listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }).collect();
+        for listener in listeners {
+            servers.push(if args.proxy_protocol {
+                tokio::spawn(serve_proxied(listener, state.clone(), shutdown_rx.clone()))
+            } else {
+                tokio::spawn(serve(listener, state.clone(), shutdown_rx.clone()))
+            });
+        }
+    }
+    // run prometheus
+    tokio::select! {
+        _ = run_forever(&usernames, &metrics, &labels, &targets, args.memory_unit, &output, &registries) => { },
+        sigterm = shutdown => {
+            info!("Shutting down");
+            if sigterm && args.flush_on_sigterm {
+                scrape(&usernames, &metrics, &labels, &targets, args.memory_unit, &output, &registries).await;
+                if let Output::Http = output {
+                    // the listeners stay open, and no more scans run, so
+                    // scrapes in the grace period see the flushed gauges
+                    info!("Serving the final scan for {}s", args.flush_grace);
+                    sleep(Duration::from_secs_f64(args.flush_grace)).await;
+                }
+            }
+        }
+    }
+    let _ = shutdown_tx.send(true);
+    for server in servers {
+        if let Err(e) = server.await {
+            error!("server task failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures::{Fixture, FixtureProcfs, UPTIME};
+
+    #[test]
+    fn port_zero_binds_an_ephemeral_port() {
+        let listener = bind_listener(SocketAddr::from(([127, 0, 0, 1], 0)), true, false).unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), 0);
+    }
+
+    #[test]
+    fn histogram_buckets_must_increase() {
+        assert!(check_buckets(&[1.0, 5.0]).is_ok());
+        assert!(check_buckets(&[5.0, 1.0]).is_err());
+        assert!(check_buckets(&[1.0, 1.0]).is_err());
+        assert!(check_buckets(&[1.0, f64::NAN]).is_err());
+    }
+
+    const LABELS: Labels = Labels{job: "j", hostgroup: "g", instance: "i"};
+
+    fn scan_options(args: &[&str], root: &Path) -> ScanOptions {
+        let args = Args::parse_from(["proc-mem-to-prom"].iter().chain(args));
+        ScanOptions{root: root.to_path_buf(), ..ScanOptions::from_args(&args)}
+    }
+
+    fn register(registry: &Registry) -> Metrics {
+        let options = MetricsOptions {
+            label_names: DEFAULT_LABEL_NAMES,
+            unit: MemoryUnit::Bytes,
+            user_labels: vec!["username"],
+            ema_alpha: None,
+            omit_zero_swap: false,
+            emit_deltas: false,
+            extra_status_fields: Vec::new(),
+            count_churn: false,
+            rss_window: None,
+            rss_histogram: None
+        };
+        Metrics::new(registry, registry, &options).unwrap()
+    }
+
+    /// The value of the series of `name` with these labels, besides the
+    /// job, hostgroup, and instance.
+    fn value(registry: &Registry, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        // gathered labels are sorted by name
+        let mut labels = labels.to_vec();
+        labels.sort();
+        let family = registry.gather().into_iter().find(|family| family.get_name() == name)?;
+        let metric = family.get_metric().iter().find(|m| {
+            let extra: Vec<_> = m.get_label().iter()
+                .filter(|l| !DEFAULT_LABEL_NAMES.contains(&l.get_name()))
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            extra == labels
+        })?;
+        Some(metric.get_gauge().get_value())
+    }
+
+    #[test]
+    fn scan_a_fixture_procfs() {
+        let started = |seconds_ago: f64| ((UPTIME - seconds_ago) * proc_mem_to_prom::clock::ticks_per_second() as f64) as u64;
+        let procfs = FixtureProcfs::new("scan", &[
+            Fixture{ppid: 0, cmdline: vec!["/sbin/init".to_string()], ..Fixture::new(1, 0)},
+            // a kernel thread
+            Fixture{ppid: 0, name: "kthreadd".to_string(), rss_kib: None, cmdline: Vec::new(), ..Fixture::new(2, 0)},
+            // a batch job on pts/3 of a uid with no user
+            Fixture{nice: 10, policy: 3, tty_nr: 136 << 8 | 3, ..Fixture::new(30, 54321)},
+            // younger than --min-process-age
+            Fixture{starttime: started(5.0), ..Fixture::new(31, 0)}
+        ]).unwrap();
+        let scan = scan_options(&["--group-by-tty", "--min-process-age", "60"], &procfs.root);
+        let registry = Registry::new();
+        let metrics = register(&registry);
+        let usernames = UsersCache::new();
+
+        assert!(procs(&usernames, &metrics, &LABELS, &scan, MemoryUnit::Bytes));
+        assert_eq!(value(&registry, "node_total_processes", &[]), Some(3.0));
+        assert_eq!(value(&registry, "node_user_processes", &[("username", "root")]), Some(2.0));
+        // the kernel thread has no RSS
+        assert_eq!(value(&registry, "node_user_processes_rss", &[("username", "root")]), Some(1024.0 * 1024.0));
+        assert_eq!(value(&registry, "node_user_processes", &[("username", "unknown")]), Some(1.0));
+        assert_eq!(value(&registry, "node_user_processes_min_nice", &[("username", "unknown")]), Some(10.0));
+        assert_eq!(value(&registry, "node_user_processes_by_sched_class", &[("username", "unknown"), ("class", "batch")]), Some(1.0));
+        assert_eq!(value(&registry, "node_tty_processes", &[("tty", "pts/3")]), Some(1.0));
+        assert_eq!(value(&registry, "node_tty_processes", &[("tty", "none")]), Some(2.0));
+        assert_eq!(value(&registry, "node_processes_orphaned_uid", &[("uid", "54321")]), Some(1.0));
+
+        // the unknown user's only process exits, so its series go
+        procfs.remove(30).unwrap();
+        assert!(procs(&usernames, &metrics, &LABELS, &scan, MemoryUnit::Bytes));
+        assert_eq!(value(&registry, "node_user_processes", &[("username", "root")]), Some(2.0));
+        assert_eq!(value(&registry, "node_user_processes", &[("username", "unknown")]), None);
+        assert_eq!(value(&registry, "node_user_processes_rss", &[("username", "unknown")]), None);
+        assert_eq!(value(&registry, "node_tty_processes", &[("tty", "pts/3")]), None);
+        assert_eq!(value(&registry, "node_processes_orphaned_uid", &[("uid", "54321")]), None);
+    }
+
+    #[test]
+    fn count_threads_counts_memory_once() {
+        // the leader and three threads, two of them in uninterruptible sleep
+        let procfs = FixtureProcfs::new("threads", &[
+            Fixture{threads: 4, tasks: vec![(7, 'S'), (8, 'D'), (9, 'R'), (10, 'D')], ..Fixture::new(7, 0)}
+        ]).unwrap();
+        let scan = scan_options(&["--count-threads"], &procfs.root);
+        let (processes, _) = scan_procs(&scan).unwrap();
+        assert_eq!(processes.len(), 1);
+        assert_eq!(processes[0].blocked_threads, Some(2));
+
+        let registry = Registry::new();
+        let metrics = register(&registry);
+        assert!(procs(&UsersCache::new(), &metrics, &LABELS, &scan, MemoryUnit::Bytes));
+        assert_eq!(value(&registry, "node_user_processes", &[("username", "root")]), Some(1.0));
+        assert_eq!(value(&registry, "node_user_processes_rss", &[("username", "root")]), Some(1024.0 * 1024.0));
+        assert_eq!(value(&registry, "node_user_processes_max_threads", &[("username", "root")]), Some(4.0));
+        assert_eq!(value(&registry, "node_user_processes_blocked_threads", &[("username", "root")]), Some(2.0));
+    }
+
+    #[test]
+    fn uid_range_matching_nothing_removes_the_series() {
+        let procfs = FixtureProcfs::new("uid-range", &[Fixture::new(1, 0)]).unwrap();
+        let registry = Registry::new();
+        let metrics = register(&registry);
+        let usernames = UsersCache::new();
+        assert!(procs(&usernames, &metrics, &LABELS, &scan_options(&[], &procfs.root), MemoryUnit::Bytes));
+        assert_eq!(value(&registry, "node_user_processes", &[("username", "root")]), Some(1.0));
+
+        let scan = scan_options(&["--uid-min", "4000000"], &procfs.root);
+        assert!(procs(&usernames, &metrics, &LABELS, &scan, MemoryUnit::Bytes));
+        assert_eq!(value(&registry, "node_user_processes", &[("username", "root")]), None);
+        assert_eq!(value(&registry, "node_total_processes", &[]), Some(0.0));
+        assert_eq!(value(&registry, "proc_mem_to_prom_up", &[]), Some(1.0));
     }
 }
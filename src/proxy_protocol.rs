@@ -0,0 +1,95 @@
+//! PROXY protocol (v1 and v2) headers, as prepended by L4 load balancers.
+//!
+//! The header is read off the connection before hyper sees it. Only as
+//! many bytes as the header has are read, so the HTTP request that
+//! follows is left untouched.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// the longest v1 header allowed by the spec, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad PROXY header: {}", msg))
+}
+
+/// Read the PROXY header, returning the original client address, or None
+/// if the balancer sent none (v1 UNKNOWN or a v2 LOCAL health check).
+pub async fn read_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    // the shortest v1 header is longer than the v2 signature
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("no PROXY signature"))
+    }
+}
+
+async fn read_v1(stream: &mut TcpStream, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad v1 source address"))?;
+            let port: u16 = src_port.parse().map_err(|_| invalid("bad v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("malformed v1 header"))
+    }
+}
+
+async fn read_v2(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let [ver_cmd, family, len_hi, len_lo] = head;
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    let mut addrs = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    stream.read_exact(&mut addrs).await?;
+    match ver_cmd & 0x0f {
+        // LOCAL: the balancer's own connection, e.g. a health check
+        0 => return Ok(None),
+        1 => { },
+        _ => return Err(invalid("unsupported v2 command"))
+    }
+    match family >> 4 {
+        // AF_INET: src addr, dst addr, src port, dst port
+        1 => {
+            if addrs.len() < 12 {
+                return Err(invalid("short v2 IPv4 addresses"));
+            }
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        },
+        // AF_INET6
+        2 => {
+            if addrs.len() < 36 {
+                return Err(invalid("short v2 IPv6 addresses"));
+            }
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        },
+        // AF_UNSPEC or AF_UNIX have no address we can use
+        _ => Ok(None)
+    }
+}
@@ -0,0 +1,169 @@
+//! Prometheus remote-write output.
+//!
+//! The WriteRequest protobuf is small enough that we encode it by hand
+//! rather than generating code for it:
+//!
+//!   WriteRequest { repeated TimeSeries timeseries = 1; }
+//!   TimeSeries   { repeated Label labels = 1; repeated Sample samples = 2; }
+//!   Label        { string name = 1; string value = 2; }
+//!   Sample       { double value = 1; int64 timestamp = 2; }
+
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE},
+    Body, Client, Method, Request, Uri
+};
+use log::warn;
+use prometheus::proto::{MetricFamily, MetricType};
+use protobuf::CodedOutputStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+
+// scrapes wait for the push, so a hung endpoint mustn't stall them
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct RemoteWrite {
+    url: Uri,
+    auth: Option<String>,
+    client: Client<HttpConnector>
+}
+
+impl RemoteWrite {
+    pub fn new(url: &str, auth: Option<String>) -> Result<RemoteWrite, String> {
+        let url: Uri = url.parse().map_err(|e| format!("invalid remote-write url {}: {}", url, e))?;
+        if url.scheme_str() != Some("http") {
+            return Err(format!("unsupported remote-write url {}: only http:// is supported", url));
+        }
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(CONNECT_TIMEOUT));
+        Ok(RemoteWrite {
+            url,
+            auth,
+            client: Client::builder().build(connector)
+        })
+    }
+
+    /// Push the families, retrying once on failure.
+    pub async fn push(&self, families: &[MetricFamily]) {
+        let body = encode(families, now_ms());
+        for attempt in 1..=2 {
+            match self.send(body.clone()).await {
+                Ok(()) => return,
+                Err(e) => warn!("remote write to {} failed (attempt {}): {}", self.url, attempt, e)
+            }
+        }
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<(), String> {
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+        if let Some(auth) = &self.auth {
+            builder = builder.header(AUTHORIZATION, auth.as_str());
+        }
+        let req = builder.body(Body::from(body)).map_err(|e| e.to_string())?;
+
+        let resp = match timeout(REQUEST_TIMEOUT, self.client.request(req)).await {
+            Ok(resp) => resp.map_err(|e| e.to_string())?,
+            Err(_) => return Err(format!("no response in {:?}", REQUEST_TIMEOUT))
+        };
+        let status = resp.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(format!("server returned {}", status));
+        }
+        Ok(())
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+/// Encode the families as a snappy-compressed WriteRequest.
+pub fn encode(families: &[MetricFamily], timestamp_ms: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut request);
+        for family in families {
+            for (labels, value) in samples(family) {
+                let series = encode_series(&labels, value, timestamp_ms);
+                os.write_bytes(1, &series).unwrap();
+            }
+        }
+        os.flush().unwrap();
+    }
+    snap::raw::Encoder::new().compress_vec(&request).unwrap()
+}
+
+/// Flatten a family into (labels, value) pairs, one per time series.
+fn samples(family: &MetricFamily) -> Vec<(Vec<(String, String)>, f64)> {
+    let name = family.get_name();
+    let mut out = Vec::new();
+    for m in family.get_metric() {
+        let labels: Vec<(String, String)> = m.get_label().iter()
+            .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+            .collect();
+        let series = |suffix: &str, extra: Option<(&str, String)>| {
+            let mut l = labels.clone();
+            l.push(("__name__".to_string(), format!("{}{}", name, suffix)));
+            if let Some((k, v)) = extra {
+                l.push((k.to_string(), v));
+            }
+            // remote write requires labels sorted by name
+            l.sort();
+            l
+        };
+        match family.get_field_type() {
+            MetricType::GAUGE => out.push((series("", None), m.get_gauge().get_value())),
+            MetricType::COUNTER => out.push((series("", None), m.get_counter().get_value())),
+            MetricType::UNTYPED => out.push((series("", None), m.get_untyped().get_value())),
+            MetricType::HISTOGRAM => {
+                let h = m.get_histogram();
+                for b in h.get_bucket() {
+                    out.push((series("_bucket", Some(("le", b.get_upper_bound().to_string()))),
+                        b.get_cumulative_count() as f64));
+                }
+                out.push((series("_bucket", Some(("le", "+Inf".to_string()))), h.get_sample_count() as f64));
+                out.push((series("_sum", None), h.get_sample_sum()));
+                out.push((series("_count", None), h.get_sample_count() as f64));
+            },
+            MetricType::SUMMARY => {
+                let s = m.get_summary();
+                out.push((series("_sum", None), s.get_sample_sum()));
+                out.push((series("_count", None), s.get_sample_count() as f64));
+            }
+        }
+    }
+    out
+}
+
+fn encode_series(labels: &[(String, String)], value: f64, timestamp_ms: i64) -> Vec<u8> {
+    let mut series = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut series);
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            {
+                let mut ls = CodedOutputStream::vec(&mut label);
+                ls.write_string(1, name).unwrap();
+                ls.write_string(2, value).unwrap();
+                ls.flush().unwrap();
+            }
+            os.write_bytes(1, &label).unwrap();
+        }
+        let mut sample = Vec::new();
+        {
+            let mut ss = CodedOutputStream::vec(&mut sample);
+            ss.write_double(1, value).unwrap();
+            ss.write_int64(2, timestamp_ms).unwrap();
+            ss.flush().unwrap();
+        }
+        os.write_bytes(2, &sample).unwrap();
+        os.flush().unwrap();
+    }
+    series
+}
@@ -0,0 +1,64 @@
+//! Signals sent to the running exporter.
+
+#[path = "../src/fixtures.rs"]
+mod fixtures;
+
+use fixtures::{Fixture, FixtureProcfs};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn sigterm_lets_an_in_flight_request_finish() {
+    // a paced scan of four processes keeps /debug/processes busy for
+    // three seconds
+    let procfs = FixtureProcfs::new("shutdown", &(1..=4).map(|pid| Fixture::new(pid, 0)).collect::<Vec<_>>()).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_proc-mem-to-prom"))
+        .args(["--listen", "127.0.0.1:0", "--enable-debug-endpoints", "--max-procfs-read-rate", "1"])
+        .arg(format!("--target=root={}", procfs.root.display()))
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    let addr = loop {
+        line.clear();
+        assert_ne!(stderr.read_line(&mut line).unwrap(), 0, "exited before listening");
+        if let Some(addr) = line.trim().strip_prefix("Listening on http://") {
+            break addr.to_string();
+        }
+    };
+
+    let (sent, request_sent) = mpsc::channel();
+    let request = thread::spawn(move || {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(b"GET /debug/processes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        sent.send(()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        (response, Instant::now())
+    });
+    request_sent.recv().unwrap();
+    // give the server a moment to read the request, well inside the scan
+    thread::sleep(Duration::from_millis(200));
+    unsafe {
+        libc::kill(child.id() as i32, libc::SIGTERM);
+    }
+    let signalled = Instant::now();
+
+    let (response, answered) = request.join().unwrap();
+    let status = child.wait().unwrap();
+    let mut log = String::new();
+    stderr.read_to_string(&mut log).unwrap();
+
+    assert!(status.success(), "exited with {}: {}", status, log);
+    assert!(log.contains("Shutting down"), "{}", log);
+    assert!(answered > signalled, "answered before the signal, so not in flight");
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    let processes: Vec<serde_json::Value> = serde_json::from_str(body).unwrap();
+    assert_eq!(processes.len(), 4);
+}